@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::path::Path;
+use crate::midi;
+use crate::config::Settings;
+
+pub const USAGE: &str = "\
+Usage: midi_patch_changer [COMMAND] [OPTIONS]
+
+Commands:
+  pick [FOLDER|FILE]             Choose devices & a patch file in the device picker (the default)
+  gui --out DEVICE [--in DEVICE] [--patches FILE]
+                                 Open the patch system GUI with the given devices
+  cli --out DEVICE [--in DEVICE] [--patches FILE]
+                                 Open the patch system in the terminal
+  monitor --out DEVICE [--in DEVICE] [--patches FILE]
+                                 Print every MIDI message flowing through the patch system
+  daemon --out DEVICE [--in DEVICE] [--patches FILE]
+                                 Run without a terminal or display (eg. as a systemd service)
+  validate FILE...               Check patch files for mistakes
+  list-devices                   List MIDI devices & what identifies them, for device aliases
+  control COMMAND [ARGUMENT]     Control a running patch system, where COMMAND is one of:
+                                 next, previous, set NUMBER|NAME, current, list, panic, reload
+  import-ins INS_FILE PATCH_FILE [INSTRUMENT]
+  import-midnam MIDNAM_FILE PATCH_FILE [NAME_SET]
+  export-midnam PATCH_FILE MIDNAM_FILE [MANUFACTURER] [MODEL]
+  convert FROM_FILE TO_FILE      Convert between a patch file and a .csv file
+  help                           Show this help
+
+The devices & patch file can also be given in order, eg. `gui IN OUT [PATCH_FILE]`, with '-' for no MIDI IN device.
+
+Options:
+  --in DEVICE                    MIDI IN device path or alias ('-' for none)
+  --out DEVICE                   MIDI OUT device path or alias
+  --patches FILE                 Patch file (or for `pick`, a folder of patch files)
+  --start-patch NUMBER|NAME      Patch to select first, instead of the first in the file
+  --config FILE|FOLDER           Settings file, or folder of config files, instead of ~/.config/midi-patch-changer
+  --set KEY=VALUE                Override a setting from the settings file (can be repeated)
+  --control-socket PATH          Socket for control commands, instead of $XDG_RUNTIME_DIR/midi-patch-changer.sock
+  --osc-port PORT                UDP port to listen on for OSC messages (eg. from TouchOSC or a lighting desk)
+  --web-port PORT                TCP port to serve a remote control page for phones on
+  --auto-start SECONDS           Start the device picker's last selection automatically
+  --channel-base 0|1             Whether patch file channels count from 0 or 1
+  --running-status               Batch output using running status, for slow links
+  --record-trigger TYPE:NUMBER[:CHANNEL]
+  --play-trigger TYPE:NUMBER[:CHANNEL]
+                                 Toggle recording/playback from a MIDI controller (TYPE is note, cc or pc)
+  --next-trigger TYPE:NUMBER[:CHANNEL]
+  --previous-trigger TYPE:NUMBER[:CHANNEL]
+                                 Change patch from a MIDI controller
+  --record-format 0|1            Standard MIDI File type for recordings
+  --recording-folder FOLDER      Where recordings are saved, instead of the current folder
+  --play FILE                    Standard MIDI File to play to MIDI OUT
+  --type KIND, --channel 1-16    Only show some messages in the monitor
+  --thru-drop KIND[,KIND...]     Don't pass these kinds of message from MIDI IN thru to MIDI OUT
+  --thru-channels N[,N...]       Only pass messages on these channels from MIDI IN thru to MIDI OUT
+  --panic-on-patch-change        Send all notes off before each new patch
+  --panic-on-exit                Send all notes off when closing the devices
+  -h, --help                     Show this help";
+
+#[derive(Clone, Copy)]
+pub enum Mode {
+    Cli,
+    Gui,
+    Monitor,
+    Daemon
+}
+
+/// The devices & patch file to open
+pub struct Devices {
+    pub midi_in: Option<String>,
+    pub midi_out: String,
+    pub patch_file: Option<String>
+}
+
+pub enum Command {
+    /// Open the device picker, listing the files in a folder (or a single file) as patch files
+    Pick { patches: String, auto_start: Option<u32> },
+    Run(Mode, Devices),
+    Validate(Vec<String>),
+    ListDevices,
+    /// Send a command to the control socket of a running patch system
+    Control(String),
+    ImportIns { ins_file: String, patch_file: String, instrument: Option<String> },
+    ImportMidnam { midnam_file: String, patch_file: String, name_set: Option<String> },
+    ExportMidnam { patch_file: String, midnam_file: String, manufacturer: String, model: String },
+    Convert { from: String, to: String },
+    Help
+}
+
+/// Everything given on the command line
+pub struct Invocation {
+    pub command: Command,
+    pub options: midi::DeviceOptions,
+    pub monitor_filter: midi::MonitorFilter,
+    pub settings: Settings
+}
+
+/// Parse the command line (including the program name), accepting options anywhere
+pub fn parse(mut args: Vec<String>) -> Result<Invocation, Box<dyn Error>> {
+    if remove_flag(&mut args, "--help") || remove_flag(&mut args, "-h") {
+        return Ok(Invocation {
+            command: Command::Help,
+            options: midi::DeviceOptions::default(),
+            monitor_filter: midi::MonitorFilter::default(),
+            settings: Settings::default()
+        });
+    }
+    let config = remove_option(&mut args, "--config")?;
+    let mut overrides = Vec::new();
+    while let Some(assignment) = remove_option(&mut args, "--set")? {
+        overrides.push(assignment);
+    }
+    let mut settings = Settings::load(config.as_deref(), &overrides)?;
+    if let Some(path) = remove_option(&mut args, "--control-socket")? {
+        settings.control_socket = Some(path);
+    }
+    if let Some(port) = remove_option(&mut args, "--osc-port")? {
+        settings.osc_port = Some(port.parse::<u16>().ok().filter(|port| *port != 0).ok_or(format!("Invalid OSC port '{}', expected 1-65535", port))?);
+    }
+    if let Some(port) = remove_option(&mut args, "--web-port")? {
+        settings.web_port = Some(port.parse::<u16>().ok().filter(|port| *port != 0).ok_or(format!("Invalid web port '{}', expected 1-65535", port))?);
+    }
+    // options given here take priority over the settings
+    let mut options = midi::DeviceOptions::default();
+    if remove_flag(&mut args, "--running-status") || settings.running_status {
+        options.output_mode = midi::OutputMode::RunningStatus;
+    }
+    if let Some(trigger) = remove_option(&mut args, "--record-trigger")?.or(settings.record_trigger.clone()) {
+        options.triggers.push(midi::Trigger::parse(&trigger, midi::TriggerAction::ToggleRecording)?);
+    }
+    if let Some(trigger) = remove_option(&mut args, "--play-trigger")?.or(settings.play_trigger.clone()) {
+        options.triggers.push(midi::Trigger::parse(&trigger, midi::TriggerAction::TogglePlayback)?);
+    }
+    if let Some(trigger) = remove_option(&mut args, "--next-trigger")?.or(settings.next_trigger.clone()) {
+        options.triggers.push(midi::Trigger::parse(&trigger, midi::TriggerAction::NextPatch)?);
+    }
+    if let Some(trigger) = remove_option(&mut args, "--previous-trigger")?.or(settings.previous_trigger.clone()) {
+        options.triggers.push(midi::Trigger::parse(&trigger, midi::TriggerAction::PreviousPatch)?);
+    }
+    options.playback_file = remove_option(&mut args, "--play")?;
+    let auto_start = match remove_option(&mut args, "--auto-start")? {
+        Some(seconds) => Some(seconds.parse::<u32>().map_err(|_| format!("Invalid auto start delay '{}', expected a number of seconds", seconds))?),
+        None => settings.auto_start
+    };
+    let base = remove_option(&mut args, "--channel-base")?.unwrap_or(settings.channel_base.to_string());
+    options.channel_base = midi::ChannelBase::parse(&base).ok_or(format!("Invalid channel base '{}', expected 0 or 1", base))?;
+    let format = remove_option(&mut args, "--record-format")?.unwrap_or(settings.record_format.to_string());
+    options.record_format = midi::SmfFormat::parse(&format).ok_or(format!("Invalid recording format '{}', expected 0 or 1", format))?;
+    if let Some(folder) = remove_option(&mut args, "--recording-folder")?.or(settings.recording_folder.clone()) {
+        options.recording_folder = folder;
+    }
+    let monitor_filter = midi::MonitorFilter {
+        kind: match remove_option(&mut args, "--type")?.or(settings.monitor_type.clone()) {
+            Some(kind) => Some(midi::MessageKind::parse(&kind).ok_or(format!("Invalid message type '{}', expected one of: note, cc, pc, bend, pressure, sysex, clock, system", kind))?),
+            None => None
+        },
+        channel: match remove_option(&mut args, "--channel")?.or(settings.monitor_channel.map(|ch| ch.to_string())) {
+            Some(channel) => match channel.parse::<u8>() {
+                Ok(ch) if (1..=16).contains(&ch) => Some(ch),
+                _ => return Err(format!("Invalid channel '{}', expected 1-16", channel).into())
+            },
+            None => None
+        }
+    };
+    let drop = match remove_option(&mut args, "--thru-drop")? {
+        Some(kinds) => kinds.split(',').map(|kind| kind.trim().to_string()).collect(),
+        None => settings.thru_drop.clone()
+    };
+    for kind in drop {
+        options.thru_filter.drop.push(midi::MessageKind::parse(&kind).ok_or(format!("Invalid message type '{}' to drop, expected one of: note, cc, pc, bend, pressure, sysex, clock, system", kind))?);
+    }
+    options.thru_filter.channels = match remove_option(&mut args, "--thru-channels")? {
+        Some(channels) => channels.split(',').map(|channel| {
+            let channel = channel.trim();
+            channel.parse::<u8>().ok().filter(|ch| (1..=16).contains(ch)).ok_or(format!("Invalid thru channel '{}', expected 1-16", channel))
+        }).collect::<Result<_, _>>()?,
+        None => settings.thru_channels.clone()
+    };
+    // channels from the settings file are numbers already, but may still be out of range
+    if let Some(channel) = options.thru_filter.channels.iter().find(|ch| !(1..=16).contains(*ch)) {
+        return Err(format!("Invalid thru channel '{}', expected 1-16", channel).into());
+    }
+    options.panic = midi::PanicOptions {
+        on_patch_change: remove_flag(&mut args, "--panic-on-patch-change") || settings.panic_on_patch_change,
+        on_exit: remove_flag(&mut args, "--panic-on-exit") || settings.panic_on_exit,
+        sound_off: settings.panic_sound_off
+    };
+    let start_patch = remove_option(&mut args, "--start-patch")?;
+    let midi_in = remove_option(&mut args, "--in")?;
+    let midi_out = remove_option(&mut args, "--out")?;
+    let patches = remove_option(&mut args, "--patches")?;
+    if let Some(unknown) = args.iter().skip(1).find(|arg| arg.starts_with("--")) {
+        return Err(format!("Unknown option '{}', see --help", unknown).into());
+    }
+
+    let name = args.get(1).cloned().unwrap_or_default();
+    let rest = args.get(2..).unwrap_or_default();
+    let gave_devices = midi_in.is_some() || midi_out.is_some();
+    let gave_patches = patches.is_some() || start_patch.is_some();
+    options.start_patch = start_patch.or(settings.start_patch.clone());
+    let command = match name.as_str() {
+        "gui" => Command::Run(Mode::Gui, devices(&name, rest, midi_in, midi_out, patches, &settings)?),
+        "cli" => Command::Run(Mode::Cli, devices(&name, rest, midi_in, midi_out, patches, &settings)?),
+        "monitor" => Command::Run(Mode::Monitor, devices(&name, rest, midi_in, midi_out, patches, &settings)?),
+        "daemon" => Command::Run(Mode::Daemon, devices(&name, rest, midi_in, midi_out, patches, &settings)?),
+        "pick" => {
+            expect_count(&name, rest, 0, 1)?;
+            let patches = match (patches, rest.first()) {
+                (Some(_), Some(_)) => return Err("The patch folder was given twice (as '--patches' and as an argument)".into()),
+                (Some(patches), None) => patches,
+                (None, Some(folder)) => folder.clone(),
+                (None, None) => settings.patches.clone()
+            };
+            Command::Pick { patches, auto_start }
+        },
+        "validate" => {
+            if rest.is_empty() {
+                return Err("'validate' needs the patch files to check, see --help".into());
+            }
+            Command::Validate(rest.to_vec())
+        },
+        "list-devices" | "devices" => {
+            expect_count(&name, rest, 0, 0)?;
+            Command::ListDevices
+        },
+        "control" => {
+            if rest.is_empty() {
+                return Err("'control' needs a command to send, see --help".into());
+            }
+            Command::Control(rest.join(" "))
+        },
+        "import-ins" => {
+            expect_count(&name, rest, 2, 3)?;
+            Command::ImportIns { ins_file: rest[0].clone(), patch_file: rest[1].clone(), instrument: rest.get(2).cloned() }
+        },
+        "import-midnam" => {
+            expect_count(&name, rest, 2, 3)?;
+            Command::ImportMidnam { midnam_file: rest[0].clone(), patch_file: rest[1].clone(), name_set: rest.get(2).cloned() }
+        },
+        "export-midnam" => {
+            expect_count(&name, rest, 2, 4)?;
+            Command::ExportMidnam {
+                patch_file: rest[0].clone(),
+                midnam_file: rest[1].clone(),
+                manufacturer: rest.get(2).cloned().unwrap_or_else(|| String::from("Unknown")),
+                model: rest.get(3).cloned().unwrap_or_else(|| String::from("Unknown"))
+            }
+        },
+        "convert" => {
+            expect_count(&name, rest, 2, 2)?;
+            Command::Convert { from: rest[0].clone(), to: rest[1].clone() }
+        },
+        "help" => Command::Help,
+        "" => Command::Pick { patches: patches.unwrap_or_else(|| settings.patches.clone()), auto_start },
+        // without a command, the only argument is the patch folder for the device picker
+        folder if rest.is_empty() && Path::new(folder).exists() && patches.is_none() => Command::Pick { patches: folder.to_string(), auto_start },
+        unknown => return Err(format!("Unknown command '{}', see --help", unknown).into())
+    };
+    let opens_devices = matches!(command, Command::Run(..));
+    if gave_devices && !opens_devices {
+        return Err(format!("'--in' and '--out' cannot be used with '{}', only with gui, cli, monitor or daemon", name).into());
+    }
+    if gave_patches && !opens_devices && !matches!(command, Command::Pick { .. } | Command::Help) {
+        return Err(format!("'--patches' and '--start-patch' cannot be used with '{}', only with gui, cli, monitor, daemon or pick", name).into());
+    }
+    Ok(Invocation { command, options, monitor_filter, settings })
+}
+
+/// The devices for gui/cli/monitor/daemon, either in order as `IN OUT [PATCH_FILE]`, or from the flags falling back to the settings
+fn devices(command: &str, rest: &[String], midi_in: Option<String>, midi_out: Option<String>, patches: Option<String>, settings: &Settings) -> Result<Devices, Box<dyn Error>> {
+    let (midi_in, midi_out, patch_file) = if rest.is_empty() {
+        let midi_out = midi_out.or(settings.midi_out.clone()).ok_or(format!("'{}' needs a MIDI OUT device (--out DEVICE), see --help", command))?;
+        (midi_in.or(settings.midi_in.clone()), midi_out, patches)
+    } else if midi_in.is_some() || midi_out.is_some() {
+        return Err(format!("Unexpected argument '{}', give the devices either with '--in'/'--out' or in order, see --help", rest[0]).into());
+    } else {
+        expect_count(command, rest, 2, 3)?;
+        let patch_file = match (patches, rest.get(2)) {
+            (Some(_), Some(_)) => return Err("The patch file was given twice (as '--patches' and as an argument)".into()),
+            (patches, file) => patches.or_else(|| file.cloned())
+        };
+        (Some(rest[0].clone()), rest[1].clone(), patch_file)
+    };
+    Ok(Devices {
+        midi_in: midi_in.filter(|midi_in| midi_in != "-"),
+        midi_out,
+        patch_file
+    })
+}
+
+fn expect_count(command: &str, rest: &[String], min: usize, max: usize) -> Result<(), Box<dyn Error>> {
+    if rest.len() < min {
+        let at_least = if max > min { "at least " } else { "" };
+        Err(format!("'{}' needs {}{} arguments, see --help", command, at_least, min).into())
+    } else if rest.len() > max {
+        Err(format!("Unexpected argument '{}' for '{}', see --help", rest[max], command).into())
+    } else {
+        Ok(())
+    }
+}
+
+fn remove_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != before
+}
+
+fn remove_option(args: &mut Vec<String>, option: &str) -> Result<Option<String>, Box<dyn Error>> {
+    match args.iter().position(|arg| arg == option) {
+        Some(index) if index + 1 < args.len() => {
+            let value = args.remove(index + 1);
+            args.remove(index);
+            Ok(Some(value))
+        },
+        Some(_) => Err(format!("Missing value for '{}'", option).into()),
+        None => Ok(None)
+    }
+}
@@ -1,23 +1,99 @@
 use crate::midi;
+use crate::config::KeyBindings;
+use crate::control::Remote;
 use console::{Term, Key};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-pub fn run(device: &mut midi::ThruDevice) {
-    let term = Term::stdout();
+/// Triggers from MIDI IN are only acted on when status is checked, so this is also their worst case delay
+const STATUS_INTERVAL: Duration = Duration::from_millis(50);
+
+pub fn run(device: &mut midi::ThruDevice, keys: &KeyBindings, remotes: &[Box<dyn Remote>]) {
     match device.current_patch() {
         Some((number, patch)) => println!("#{} {}", number, patch.name),
         None => println!("**NO PATCHES**")
     };
-    while let Ok(k) = term.read_key() {
-        match k {
-            _ if !device.has_patches() => println!("**NO PATCHES**"),
-            Key::Backspace => match device.increment_patch(-1) {
-                Some((number, patch)) => println!("<<< #{} {}", number, patch.name),
-                None => println!("**FIRST PATCH**")
-            },
-            _ => match device.increment_patch(1) {
-                Some((number, patch)) => println!("#{} {}", number, patch.name),
-                None => println!("**LAST PATCH**")
+    let (key_tx, key_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let term = Term::stdout();
+        while let Ok(k) = term.read_key() {
+            if key_tx.send(k).is_err() {
+                break;
             }
+        }
+    });
+    loop {
+        let k = match key_rx.recv_timeout(STATUS_INTERVAL) {
+            Ok(k) => k,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                print_status(device, remotes);
+                continue;
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => break
         };
+        print_status(device, remotes);
+        if is_bound(&keys.record, &k) {
+            println!("NOTE: {}", device.toggle_recording());
+        } else if is_bound(&keys.play, &k) && device.can_play() {
+            println!("NOTE: {}", device.toggle_playback());
+        } else if is_bound(&keys.previous, &k) || is_bound(&keys.next, &k) || is_unbound(keys, &k) {
+            if !device.has_patches() {
+                println!("**NO PATCHES**");
+            } else if is_bound(&keys.previous, &k) {
+                match device.increment_patch(-1) {
+                    Some((number, patch)) => println!("<<< #{} {}", number, patch.name),
+                    None => println!("**FIRST PATCH**")
+                }
+            } else {
+                match device.increment_patch(1) {
+                    Some((number, patch)) => println!("#{} {}", number, patch.name),
+                    None => println!("**LAST PATCH**")
+                }
+            }
+        }
+    }
+}
+
+/// True if the key is in the list, matching characters case-insensitively and other keys by name (eg. "Backspace")
+fn is_bound(names: &[String], key: &Key) -> bool {
+    names.iter().any(|name| {
+        let mut chars = name.chars();
+        match (key, chars.next(), chars.next()) {
+            (Key::Char(c), Some(bound), None) => c.eq_ignore_ascii_case(&bound),
+            (Key::Char(' '), _, _) => name.eq_ignore_ascii_case("space"),
+            _ => name.eq_ignore_ascii_case(&format!("{:?}", key))
+        }
+    })
+}
+
+/// True if the key isn't bound to anything and "any" key moves to the next patch
+fn is_unbound(keys: &KeyBindings, key: &Key) -> bool {
+    keys.next.iter().any(|name| name == "any") && ![&keys.next, &keys.previous, &keys.record, &keys.play].iter().any(|names| is_bound(names, key))
+}
+
+/// Print any changes in the device, and carry out any commands from other programs
+fn print_status(device: &mut midi::ThruDevice, remotes: &[Box<dyn Remote>]) {
+    for status in device.check_status() {
+        println!("NOTE: {}", status);
+    }
+    for remote in remotes {
+        for handled in remote.handle_requests(Some(device)) {
+            println!("NOTE: {}", handled);
+        }
+    }
+}
+
+pub fn monitor(device: &mut midi::ThruDevice, filter: &midi::MonitorFilter, remotes: &[Box<dyn Remote>]) {
+    let events = device.monitor();
+    loop {
+        match events.recv_timeout(STATUS_INTERVAL) {
+            Ok(event) => if filter.matches(&event) {
+                println!("{}", event);
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => break
+        }
+        print_status(device, remotes);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,250 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use serde_json::Value;
+use crate::midi::DeviceAliases;
+use crate::control;
+
+const APP_NAME: &str = "midi-patch-changer";
+const SELECTION_FILE: &str = "last-selection.json";
+const ALIASES_FILE: &str = "devices.json";
+const SETTINGS_FILE: &str = "config.json";
+
+/// Set by --config, replacing the usual config directory
+static DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use another directory for config files, for the rest of the program
+fn use_dir(dir: PathBuf) {
+    let _ = DIR_OVERRIDE.set(dir);
+}
+
+/// The directory for this app's config files, following the XDG base directory spec unless another has been chosen
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = DIR_OVERRIDE.get() {
+        return Some(dir.clone());
+    }
+    match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir).join(APP_NAME)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join(APP_NAME))
+    }
+}
+
+/// Defaults for everything which can be chosen on the command line, and the look & feel of the GUI
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Used by gui/cli/monitor when no devices are given, and preselected in the device picker the first time
+    pub midi_in: Option<String>,
+    pub midi_out: Option<String>,
+    /// Folder (or single file) of patch files listed by the device picker
+    pub patches: String,
+    #[serde(deserialize_with = "name_or_number")]
+    pub start_patch: Option<String>,
+    pub channel_base: u8,
+    pub running_status: bool,
+    pub record_format: u8,
+    /// Where recordings are saved, otherwise the current folder
+    pub recording_folder: Option<String>,
+    pub record_trigger: Option<String>,
+    pub play_trigger: Option<String>,
+    pub next_trigger: Option<String>,
+    pub previous_trigger: Option<String>,
+    pub auto_start: Option<u32>,
+    pub fullscreen: bool,
+    /// How long the screen must be held to show the patch system menu
+    pub long_press_ms: u64,
+    /// Size of everything except the current patch, otherwise scaled to the screen
+    pub text_size: Option<u16>,
+    /// Size of the current patch, otherwise filling the space left
+    pub patch_text_size: Option<u16>,
+    pub keys: KeyBindings,
+    /// Messages shown by the monitor
+    pub monitor_type: Option<String>,
+    pub monitor_channel: Option<u8>,
+    /// Kinds of message from MIDI IN which are not passed thru (as for monitor_type)
+    pub thru_drop: Vec<String>,
+    /// Channels (1-16) passed thru from MIDI IN, otherwise all of them
+    pub thru_channels: Vec<u8>,
+    /// When a panic is sent without being asked for, and whether it includes All Sound Off
+    pub panic_on_patch_change: bool,
+    pub panic_on_exit: bool,
+    pub panic_sound_off: bool,
+    /// Listen for commands from scripts & other programs while the patch system is running
+    pub control: bool,
+    /// Socket to listen on, otherwise in $XDG_RUNTIME_DIR
+    pub control_socket: Option<String>,
+    /// UDP port to listen on for OSC messages, otherwise OSC is off
+    pub osc_port: Option<u16>,
+    /// TCP port to serve the remote control page on, otherwise it is off
+    pub web_port: Option<u16>
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            midi_in: None,
+            midi_out: None,
+            patches: String::from("."),
+            start_patch: None,
+            channel_base: 0,
+            running_status: false,
+            record_format: 1,
+            recording_folder: None,
+            record_trigger: None,
+            play_trigger: None,
+            next_trigger: None,
+            previous_trigger: None,
+            auto_start: None,
+            fullscreen: true,
+            long_press_ms: 1500,
+            text_size: None,
+            patch_text_size: None,
+            keys: KeyBindings::default(),
+            monitor_type: None,
+            monitor_channel: None,
+            thru_drop: Vec::new(),
+            thru_channels: Vec::new(),
+            panic_on_patch_change: false,
+            panic_on_exit: false,
+            panic_sound_off: true,
+            control: true,
+            control_socket: None,
+            osc_port: None,
+            web_port: None
+        }
+    }
+}
+
+/// Keys used by the patch system CLI, each either a single character or a key name (eg. "Backspace", "Enter", "PageDown").
+/// "any" matches every key not bound to something else.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub next: Vec<String>,
+    pub previous: Vec<String>,
+    pub record: Vec<String>,
+    pub play: Vec<String>
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            next: vec![String::from("any")],
+            previous: vec![String::from("Backspace")],
+            record: vec![String::from("r")],
+            play: vec![String::from("p")]
+        }
+    }
+}
+
+impl Settings {
+    /// Load the settings file, from the config directory or the file (or directory) given with --config,
+    /// then apply each `KEY=VALUE` given with --set
+    pub fn load(config: Option<&str>, overrides: &[String]) -> Result<Self, Box<dyn Error>> {
+        let file = match config.map(Path::new) {
+            Some(dir) if dir.is_dir() => {
+                use_dir(dir.to_path_buf());
+                Some(dir.join(SETTINGS_FILE))
+            },
+            Some(file) => {
+                if !file.exists() {
+                    return Err(format!("Cannot read from '{}': no such config file", file.display()).into());
+                }
+                use_dir(file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf());
+                Some(file.to_path_buf())
+            },
+            None => config_dir().map(|dir| dir.join(SETTINGS_FILE))
+        };
+        let mut settings = match file.filter(|file| file.exists()) {
+            Some(file) => {
+                let json = fs::read_to_string(&file).map_err(|e| format!("Cannot read from '{}': {}", file.display(), e))?;
+                let value: Value = serde_json::from_str(&json).map_err(|e| format!("Cannot parse settings from '{}': {}", file.display(), e))?;
+                // check the file on its own, so mistakes in it are reported against it
+                serde_json::from_value::<Settings>(value.clone()).map_err(|e| format!("Cannot load settings from '{}': {}", file.display(), e))?;
+                value
+            },
+            None => Value::Object(Default::default())
+        };
+        for assignment in overrides {
+            set_value(&mut settings, assignment)?;
+        }
+        Ok(serde_json::from_value(settings).map_err(|e| format!("Invalid --set: {}", e))?)
+    }
+
+    /// The path of the control socket, whether or not it is enabled
+    pub fn control_socket(&self) -> PathBuf {
+        self.control_socket.as_ref().map(PathBuf::from).unwrap_or_else(control::default_socket)
+    }
+}
+
+/// A patch can be chosen by its name or its number
+fn name_or_number<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    match <Option<Value> as serde::Deserialize>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(name)) => Ok(Some(name)),
+        Some(Value::Number(number)) => Ok(Some(number.to_string())),
+        Some(other) => Err(serde::de::Error::custom(format!("expected a patch name or number, found {}", other)))
+    }
+}
+
+/// Apply `KEY=VALUE` (where KEY may be nested, eg. `keys.next`) to the settings, treating VALUE as JSON if it parses, otherwise as a string
+fn set_value(settings: &mut Value, assignment: &str) -> Result<(), String> {
+    let (key, value) = assignment.split_once('=').ok_or(format!("Invalid --set '{}', expected KEY=VALUE", assignment))?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    let mut target = settings;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        let object = target.as_object_mut().ok_or(format!("Invalid --set '{}', '{}' is not a group of settings", assignment, part))?;
+        if parts.peek().is_none() {
+            object.insert(part.to_string(), value);
+            return Ok(());
+        }
+        target = object.entry(part).or_insert_with(|| Value::Object(Default::default()));
+    }
+    Ok(())
+}
+
+/// The devices & patch file chosen the last time the device picker was used
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Selection {
+    pub midi_in: Option<String>,
+    pub midi_out: Option<String>,
+    pub patch_file: Option<String>
+}
+
+impl Selection {
+    /// The last selection, or None if nothing has been saved yet (or it cannot be read)
+    pub fn load() -> Option<Self> {
+        let file = config_dir()?.join(SELECTION_FILE);
+        let json = fs::read_to_string(file).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let dir = config_dir().ok_or("Cannot find a config directory, HOME is not set")?;
+        fs::create_dir_all(&dir).map_err(|e| format!("Cannot create '{}': {}", dir.display(), e))?;
+        let file = dir.join(SELECTION_FILE);
+        fs::write(&file, serde_json::to_string_pretty(self)?).map_err(|e| format!("Cannot write to '{}': {}", file.display(), e))?;
+        Ok(())
+    }
+}
+
+/// The file mapping friendly names to MIDI devices
+pub fn aliases_file() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(ALIASES_FILE))
+}
+
+/// The device aliases in the config directory, or none if there is no aliases file
+pub fn load_aliases() -> Result<DeviceAliases, Box<dyn Error>> {
+    let file = match aliases_file() {
+        Some(file) => file,
+        None => return Ok(DeviceAliases::default())
+    };
+    if !file.exists() {
+        return Ok(DeviceAliases::default());
+    }
+    let json = fs::read_to_string(&file).map_err(|e| format!("Cannot read from '{}': {}", file.display(), e))?;
+    Ok(DeviceAliases::parse(&json).map_err(|e| format!("Cannot parse device aliases from '{}': {}", file.display(), e))?)
+}
@@ -0,0 +1,212 @@
+use crate::midi;
+use crate::server;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Longest time a client waits for the patch system to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const COMMANDS: &str = "next, previous, set NUMBER|NAME, current, list, panic, reload";
+
+/// Where the control socket is, unless another path is given
+pub fn default_socket() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("midi-patch-changer.sock"),
+        None => {
+            // SAFETY: getuid has no preconditions & cannot fail
+            let uid = unsafe { libc::getuid() };
+            PathBuf::from(format!("/tmp/midi-patch-changer-{}.sock", uid))
+        }
+    }
+}
+
+/// Something listening for commands from other programs.
+/// The commands are carried out by whoever owns the device, by calling handle_requests regularly.
+pub trait Remote {
+    /// Carry out any commands waiting, returning a description of each (for logging).
+    /// Without a device (eg. while choosing devices) every command is refused.
+    fn handle_requests(&self, device: Option<&mut midi::ThruDevice>) -> Vec<String>;
+}
+
+/// A command received on the socket, waiting for the patch system to carry it out
+struct Request {
+    line: String,
+    reply: mpsc::Sender<Vec<String>>
+}
+
+/// Listens on a Unix socket for commands, one per line. Each reply is zero or more lines of output,
+/// followed by a line starting with OK or ERR.
+pub struct ControlServer {
+    path: PathBuf,
+    requests: mpsc::Receiver<Request>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl ControlServer {
+    pub fn start(path: &Path) -> Result<Self, String> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!("Cannot listen on '{}': another patch system is already listening", path.display()));
+            }
+            // left behind by a patch system which did not exit cleanly, so long as it really is a socket
+            let metadata = fs::symlink_metadata(path).map_err(|e| format!("Cannot listen on '{}': {}", path.display(), e))?;
+            if !metadata.file_type().is_socket() {
+                return Err(format!("Cannot listen on '{}': it exists and is not a socket", path.display()));
+            }
+            fs::remove_file(path).map_err(|e| format!("Cannot remove old socket '{}': {}", path.display(), e))?;
+        }
+        let listener = UnixListener::bind(path).map_err(|e| format!("Cannot listen on '{}': {}", path.display(), e))?;
+        let (tx, rx) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = server::accept_connections(listener, "midi-control", running.clone(), move |stream| serve_client(stream, tx))
+            .map_err(|e| format!("Cannot listen on '{}': {}", path.display(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            requests: rx,
+            running,
+            thread: Some(thread)
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Remote for ControlServer {
+    fn handle_requests(&self, mut device: Option<&mut midi::ThruDevice>) -> Vec<String> {
+        let mut handled = Vec::new();
+        while let Ok(request) = self.requests.try_recv() {
+            let reply = execute(&request.line, device.as_deref_mut());
+            handled.push(format!("Control: {} ({})", request.line, reply.last().map(String::as_str).unwrap_or_default()));
+            let _ = request.reply.send(reply);
+        }
+        handled
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Pass each line from a client to the patch system & write back its reply, until the client disconnects
+fn serve_client(stream: UnixStream, tx: mpsc::Sender<Request>) {
+    if stream.set_nonblocking(false).is_err() {
+        return;
+    }
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line.trim().to_string(),
+            Err(_) => return
+        };
+        if line.is_empty() {
+            continue;
+        }
+        let (reply_tx, reply_rx) = mpsc::channel();
+        if tx.send(Request { line, reply: reply_tx }).is_err() {
+            return;
+        }
+        let reply = reply_rx.recv_timeout(REPLY_TIMEOUT).unwrap_or_else(|_| vec![String::from("ERR The patch system did not answer")]);
+        for line in reply {
+            if writeln!(writer, "{}", line).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Carry out a command, returning its output & finally a line starting with OK or ERR
+pub fn execute(line: &str, device: Option<&mut midi::ThruDevice>) -> Vec<String> {
+    let device = match device {
+        Some(device) => device,
+        None => return vec![String::from("ERR No devices are open")]
+    };
+    let (command, argument) = match line.split_once(' ') {
+        Some((command, argument)) => (command, argument.trim()),
+        None => (line, "")
+    };
+    match command.to_lowercase().as_str() {
+        "next" => {
+            device.increment_patch(1);
+            current(device)
+        },
+        "previous" | "prev" => {
+            device.increment_patch(-1);
+            current(device)
+        },
+        "set" if argument.is_empty() => vec![String::from("ERR 'set' needs a patch number or name")],
+        "set" => match device.find_patch(argument) {
+            Some(index) => {
+                device.set_patch(index);
+                current(device)
+            },
+            None => vec![format!("ERR No patch '{}'", argument)]
+        },
+        "current" => current(device),
+        "list" => {
+            let mut lines: Vec<String> = device.patches().iter().enumerate().map(|(index, patch)| format!("#{} {}", index + 1, patch.name)).collect();
+            lines.push(format!("OK {} patches", lines.len()));
+            lines
+        },
+        "panic" => match device.panic() {
+            Ok(()) => vec![String::from("OK All notes off")],
+            Err(e) => vec![format!("ERR {}", e)]
+        },
+        "reload" => match device.reload_patches() {
+            Ok(count) => {
+                let mut reply = current(device);
+                reply.insert(0, format!("Reloaded {} patches", count));
+                reply
+            },
+            Err(e) => vec![format!("ERR {}", e)]
+        },
+        "help" => vec![String::from(COMMANDS), String::from("OK")],
+        other => vec![format!("ERR Unknown command '{}', expected one of: {}", other, COMMANDS)]
+    }
+}
+
+fn current(device: &midi::ThruDevice) -> Vec<String> {
+    match device.current_patch() {
+        Some((number, patch)) => vec![format!("OK #{} {}", number, patch.name)],
+        None => vec![String::from("OK No patches")]
+    }
+}
+
+/// Send a command to a running patch system & print its reply, failing if the command was refused
+pub fn send(path: &Path, command: &str) -> Result<(), Box<dyn Error>> {
+    let stream = UnixStream::connect(path).map_err(|e| format!("Cannot connect to '{}': {} (is the patch system running?)", path.display(), e))?;
+    let mut writer = stream.try_clone()?;
+    writeln!(writer, "{}", command)?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if let Some(error) = line.strip_prefix("ERR ") {
+            return Err(error.into());
+        } else if line == "OK" {
+            return Ok(());
+        } else if let Some(result) = line.strip_prefix("OK ") {
+            println!("{}", result);
+            return Ok(());
+        }
+        println!("{}", line);
+    }
+    Err("The patch system closed the connection without answering".into())
+}
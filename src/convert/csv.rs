@@ -0,0 +1,136 @@
+use crate::midi::{ChannelBase, Patch, TEMPO_RANGE};
+use std::error::Error;
+use wmidi::U7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Name,
+    Channel,
+    BankMsb,
+    BankLsb,
+    Program,
+    Tempo,
+    Group
+}
+
+impl Column {
+    const ALL: [Column; 7] = [Column::Name, Column::Channel, Column::BankMsb, Column::BankLsb, Column::Program, Column::Tempo, Column::Group];
+
+    /// Match a header cell, ignoring case, spaces & underscores (so "Bank MSB", "bank_msb" and "msb" are all the same)
+    fn parse(header: &str) -> Option<Self> {
+        let header: String = header.chars().filter(|c| !c.is_whitespace() && *c != '_').collect::<String>().to_lowercase();
+        match header.as_str() {
+            "name" | "patch" | "patchname" => Some(Column::Name),
+            "channel" | "ch" => Some(Column::Channel),
+            "msb" | "bankmsb" => Some(Column::BankMsb),
+            "lsb" | "banklsb" => Some(Column::BankLsb),
+            "program" | "prog" | "pc" => Some(Column::Program),
+            "tempo" | "bpm" => Some(Column::Tempo),
+            "group" | "bank" => Some(Column::Group),
+            _ => None
+        }
+    }
+
+    fn header(&self) -> &'static str {
+        match self {
+            Column::Name => "name",
+            Column::Channel => "channel",
+            Column::BankMsb => "bank_msb",
+            Column::BankLsb => "bank_lsb",
+            Column::Program => "program",
+            Column::Tempo => "tempo",
+            Column::Group => "group"
+        }
+    }
+}
+
+/// Read patches from a CSV file, with the columns detected from its header row (any unrecognised columns are ignored)
+pub fn import(file: &str, base: ChannelBase) -> Result<Vec<Patch>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(file)
+        .map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+    let headers = reader.headers().map_err(|e| format!("Cannot read header row of '{}': {}", file, e))?.clone();
+    let mut columns = Vec::new();
+    for (index, header) in headers.iter().enumerate() {
+        if let Some(column) = Column::parse(header) {
+            if columns.iter().any(|(_, c)| *c == column) {
+                return Err(format!("Column '{}' appears more than once in the header row of '{}'", header, file).into());
+            }
+            columns.push((index, column));
+        }
+    }
+    if !columns.iter().any(|(_, c)| *c == Column::Name) {
+        return Err(format!("The header row of '{}' must have a 'name' column", file).into());
+    }
+    let mut patches = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+        let row = record.position().map(|p| p.line()).unwrap_or_default();
+        if record.iter().all(str::is_empty) {
+            continue;
+        }
+        let cell = |column: Column| columns.iter()
+            .find(|(_, c)| *c == column)
+            .and_then(|(index, _)| record.get(*index))
+            .filter(|value| !value.is_empty());
+        let data_byte = |column: Column| -> Result<Option<U7>, String> {
+            match cell(column) {
+                Some(value) => match value.parse::<u8>().ok().and_then(|n| U7::try_from(n).ok()) {
+                    Some(n) => Ok(Some(n)),
+                    None => Err(format!("Row {}: invalid {} '{}', expected 0-127", row, column.header(), value))
+                },
+                None => Ok(None)
+            }
+        };
+        let channel = match cell(Column::Channel) {
+            Some(value) => {
+                let number = value.parse::<i64>().map_err(|_| format!("Row {}: invalid channel '{}'", row, value))?;
+                Some(base.channel(number).map_err(|e| format!("Row {}: {}", row, e))?)
+            },
+            None => None
+        };
+        let name = cell(Column::Name).ok_or_else(|| format!("Row {}: missing name", row))?;
+        let mut patch = Patch::new(name.to_string(), channel, data_byte(Column::BankMsb)?, data_byte(Column::BankLsb)?, data_byte(Column::Program)?);
+        if let Some(value) = cell(Column::Tempo) {
+            match value.parse::<f64>() {
+                Ok(bpm) if TEMPO_RANGE.contains(&bpm) => patch = patch.with_tempo(bpm),
+                _ => return Err(format!("Row {}: invalid tempo '{}', expected {}-{} BPM", row, value, TEMPO_RANGE.start(), TEMPO_RANGE.end()).into())
+            }
+        }
+        if let Some(group) = cell(Column::Group) {
+            patch = patch.with_group(group.to_string());
+        }
+        patches.push(patch);
+    }
+    Ok(patches)
+}
+
+/// Write patches to a CSV file with a header row, leaving out tempo & group columns when no patch uses them
+pub fn export(file: &str, patches: &[Patch], base: ChannelBase) -> Result<(), Box<dyn Error>> {
+    let columns: Vec<Column> = Column::ALL.iter()
+        .copied()
+        .filter(|column| match column {
+            Column::Tempo => patches.iter().any(|p| p.tempo().is_some()),
+            Column::Group => patches.iter().any(|p| p.group().is_some()),
+            _ => true
+        })
+        .collect();
+    let mut writer = csv::Writer::from_path(file).map_err(|e| format!("Cannot write to '{}': {}", file, e))?;
+    writer.write_record(columns.iter().map(Column::header))?;
+    for patch in patches {
+        let number = |value: Option<u8>| value.map(|n| n.to_string()).unwrap_or_default();
+        writer.write_record(columns.iter().map(|column| match column {
+            Column::Name => patch.name.clone(),
+            Column::Channel => number(patch.channel().map(|ch| base.number(ch))),
+            Column::BankMsb => number(patch.bank_msb()),
+            Column::BankLsb => number(patch.bank_lsb()),
+            Column::Program => number(patch.program()),
+            Column::Tempo => patch.tempo().map(|t| t.to_string()).unwrap_or_default(),
+            Column::Group => patch.group().unwrap_or_default().to_string()
+        }))?;
+    }
+    writer.flush().map_err(|e| format!("Cannot write to '{}': {}", file, e))?;
+    Ok(())
+}
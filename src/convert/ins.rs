@@ -0,0 +1,144 @@
+use crate::midi::Patch;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use wmidi::U7;
+
+/// Patch name lists and instrument definitions from a Cakewalk instrument definition (.ins) file
+struct InsFile {
+    patch_names: HashMap<String, Section>,
+    instruments: Vec<(String, Section)>
+}
+
+#[derive(Default)]
+struct Section {
+    based_on: Option<String>,
+    entries: Vec<(String, String)>
+}
+
+/// How bank numbers in an instrument definition are sent
+enum BankSelect {
+    MsbAndLsb,
+    MsbOnly,
+    LsbOnly,
+    None
+}
+
+/// Read the patches of an instrument from a .ins file, where the instrument name can be omitted
+/// if the file only defines one instrument
+pub fn import(file: &str, instrument: Option<&str>) -> Result<Vec<Patch>, Box<dyn Error>> {
+    let text = fs::read_to_string(file).map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+    let ins = parse(&text);
+    let (name, definition) = match instrument {
+        Some(name) => ins.instruments.iter().find(|(n, _)| n == name)
+            .ok_or_else(|| format!("Instrument '{}' not found in '{}', expected one of: {}", name, file, instrument_names(&ins)))?,
+        None => match ins.instruments.len() {
+            0 => return Err(format!("No instrument definitions found in '{}'", file).into()),
+            1 => &ins.instruments[0],
+            _ => return Err(format!("'{}' defines multiple instruments, please specify one of: {}", file, instrument_names(&ins)).into())
+        }
+    };
+    let bank_select = match definition.entries.iter().find(|(key, _)| key == "BankSelMethod").map(|(_, value)| value.as_str()) {
+        Some("1") => BankSelect::MsbOnly,
+        Some("2") => BankSelect::LsbOnly,
+        Some("3") => BankSelect::None,
+        _ => BankSelect::MsbAndLsb
+    };
+    let mut patches = Vec::new();
+    for (key, list_name) in &definition.entries {
+        let bank = match key.strip_prefix("Patch[").and_then(|k| k.strip_suffix(']')) {
+            Some("*") => None,
+            Some(number) => Some(number.parse::<u16>().map_err(|_| format!("Invalid bank '{}' in instrument '{}'", key, name))?),
+            None => continue
+        };
+        let (bank_msb, bank_lsb) = match (bank, &bank_select) {
+            (None, _) | (_, BankSelect::None) => (None, None),
+            (Some(bank), BankSelect::MsbAndLsb) => (Some(low_bits(bank >> 7)), Some(low_bits(bank))),
+            (Some(bank), BankSelect::MsbOnly) => (Some(low_bits(bank)), None),
+            (Some(bank), BankSelect::LsbOnly) => (None, Some(low_bits(bank)))
+        };
+        let mut programs: Vec<(U7, String)> = patch_names(&ins, list_name)?.into_iter().collect();
+        programs.sort_by_key(|(program, _)| *program);
+        for (program, patch_name) in programs {
+            patches.push(Patch::new(patch_name, None, bank_msb, bank_lsb, Some(program)));
+        }
+    }
+    Ok(patches)
+}
+
+/// The lowest 7 bits of a bank number
+fn low_bits(bank: u16) -> U7 {
+    U7::from_u8_lossy((bank & 0x7F) as u8)
+}
+
+fn instrument_names(ins: &InsFile) -> String {
+    ins.instruments.iter().map(|(name, _)| format!("'{}'", name)).collect::<Vec<String>>().join(", ")
+}
+
+/// The names of each program in a patch name list, including any it is based on
+fn patch_names(ins: &InsFile, list_name: &str) -> Result<HashMap<U7, String>, String> {
+    let mut names = HashMap::new();
+    let mut chain = Vec::new();
+    let mut next = Some(list_name.to_string());
+    while let Some(name) = next {
+        if chain.contains(&name) {
+            return Err(format!("Patch name list '{}' is based on itself", name));
+        }
+        let section = ins.patch_names.get(&name).ok_or_else(|| format!("Patch name list '{}' not found", name))?;
+        next = section.based_on.clone();
+        chain.push(name);
+        for (key, value) in &section.entries {
+            if let Some(program) = key.parse::<u8>().ok().and_then(|p| U7::try_from(p).ok()) {
+                // entries in this list override those it is based on
+                names.entry(program).or_insert_with(|| value.clone());
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn parse(text: &str) -> InsFile {
+    let mut ins = InsFile {
+        patch_names: HashMap::new(),
+        instruments: Vec::new()
+    };
+    let mut heading = String::new();
+    let mut current: Option<(String, Section)> = None;
+    for line in text.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('.') || line.starts_with('[') {
+            if let Some(section) = current.take() {
+                add_section(&mut ins, &heading, section);
+            }
+            if let Some(new_heading) = line.strip_prefix('.') {
+                heading = new_heading.to_string();
+            } else {
+                current = Some((line.trim_start_matches('[').trim_end_matches(']').to_string(), Section::default()));
+            }
+        } else if let Some((_, section)) = &mut current {
+            if let Some((key, value)) = line.split_once('=') {
+                if key == "BasedOn" {
+                    section.based_on = Some(value.trim().to_string());
+                } else {
+                    section.entries.push((key.trim().to_string(), value.trim().to_string()));
+                }
+            }
+        }
+    }
+    if let Some(section) = current.take() {
+        add_section(&mut ins, &heading, section);
+    }
+    ins
+}
+
+fn add_section(ins: &mut InsFile, heading: &str, (name, section): (String, Section)) {
+    match heading {
+        "Patch Names" => {
+            ins.patch_names.insert(name, section);
+        },
+        "Instrument Definitions" => ins.instruments.push((name, section)),
+        _ => {} // note names, controller names, etc
+    }
+}
@@ -0,0 +1,188 @@
+use crate::midi::Patch;
+use roxmltree::{Document, Node, ParsingOptions};
+use std::error::Error;
+use std::fs;
+use wmidi::{Channel, U7};
+
+const DOCTYPE: &str = r#"<!DOCTYPE MIDINameDocument PUBLIC "-//MIDI Manufacturers Association//DTD MIDINameDocument 1.0//EN" "http://www.midi.org/dtds/MIDINameDocument10.dtd">"#;
+
+/// Read every patch bank of a channel name set in a MIDI Name Document, with each patch grouped by its bank name.
+/// The name set can be omitted if the document only has one.
+/// If the name set is only available on one channel (eg. drum kits on channel 10), its patches are sent on that channel.
+pub fn import(file: &str, name_set: Option<&str>) -> Result<Vec<Patch>, Box<dyn Error>> {
+    let xml = fs::read_to_string(file).map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+    let options = ParsingOptions { allow_dtd: true, ..ParsingOptions::default() };
+    let doc = Document::parse_with_options(&xml, options).map_err(|e| format!("Cannot parse '{}': {}", file, e))?;
+    let sets: Vec<Node> = doc.descendants().filter(|n| n.has_tag_name("ChannelNameSet")).collect();
+    let set_names = || sets.iter().map(|set| format!("'{}'", set.attribute("Name").unwrap_or_default())).collect::<Vec<_>>().join(", ");
+    let set = match name_set {
+        Some(name) => sets.iter().find(|set| set.attribute("Name") == Some(name))
+            .ok_or_else(|| format!("Channel name set '{}' not found in '{}', expected one of: {}", name, file, set_names()))?,
+        None => match sets.len() {
+            0 => return Err(format!("No channel name sets found in '{}'", file).into()),
+            1 => &sets[0],
+            _ => return Err(format!("'{}' has multiple channel name sets, please specify one of: {}", file, set_names()).into())
+        }
+    };
+    let available: Vec<u8> = child(set, "AvailableForChannels").map(|channels| channels.children()
+        .filter(|n| n.has_tag_name("AvailableChannel") && n.attribute("Available") == Some("true"))
+        .filter_map(|n| n.attribute("Channel").and_then(|c| c.parse::<u8>().ok()))
+        .collect()).unwrap_or_default();
+    let channel = match available.as_slice() {
+        [number] => Some(Channel::from_index(number.wrapping_sub(1)).map_err(|_| format!("Invalid channel '{}' in '{}', expected 1-16", number, file))?),
+        _ => None
+    };
+    let mut patches = Vec::new();
+    for bank in set.children().filter(|n| n.has_tag_name("PatchBank")) {
+        let bank_name = bank.attribute("Name").unwrap_or_default().to_string();
+        let (bank_msb, bank_lsb, _) = midi_commands(child(&bank, "MIDICommands"));
+        let list = match child(&bank, "PatchNameList") {
+            Some(list) => list,
+            None => match child(&bank, "UsesPatchNameList").and_then(|uses| uses.attribute("Name")) {
+                Some(name) => doc.descendants()
+                    .find(|n| n.has_tag_name("PatchNameList") && n.attribute("Name") == Some(name))
+                    .ok_or_else(|| format!("Patch name list '{}' not found in '{}'", name, file))?,
+                None => continue
+            }
+        };
+        for patch in list.children().filter(|n| n.has_tag_name("Patch")) {
+            let name = patch.attribute("Name").ok_or_else(|| format!("Patch without a name in bank '{}'", bank_name))?;
+            // commands on the patch itself take priority over those of the bank
+            let (patch_msb, patch_lsb, patch_program) = midi_commands(child(&patch, "PatchMIDICommands"));
+            let program = match patch.attribute("ProgramChange") {
+                Some(value) => Some(value.parse::<u8>().ok().and_then(|p| U7::try_from(p).ok())
+                    .ok_or_else(|| format!("Invalid program change '{}' for patch '{}', expected 0-127", value, name))?),
+                None => patch_program
+            };
+            patches.push(Patch::new(name.to_string(), channel, patch_msb.or(bank_msb), patch_lsb.or(bank_lsb), program).with_group(bank_name.clone()));
+        }
+    }
+    Ok(patches)
+}
+
+fn child<'a, 'input>(node: &Node<'a, 'input>, tag: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(tag))
+}
+
+/// The bank select MSB, LSB & program change within a MIDICommands element, ignoring out of range values
+fn midi_commands(commands: Option<Node>) -> (Option<U7>, Option<U7>, Option<U7>) {
+    let mut result = (None, None, None);
+    if let Some(commands) = commands {
+        for command in commands.children().filter(|n| n.is_element()) {
+            let value = |attribute: &str| command.attribute(attribute).and_then(|v| v.parse::<u8>().ok()).and_then(|v| U7::try_from(v).ok());
+            match command.tag_name().name() {
+                "ControlChange" => match value("Control").map(u8::from) {
+                    Some(0) => result.0 = value("Value"),
+                    Some(32) => result.1 = value("Value"),
+                    _ => {}
+                },
+                "ProgramChange" => result.2 = value("Number"),
+                _ => {}
+            }
+        }
+    }
+    result
+}
+
+struct Bank<'a> {
+    name: String,
+    msb: Option<u8>,
+    lsb: Option<u8>,
+    patches: Vec<&'a Patch>
+}
+
+impl Bank<'_> {
+    /// Patch name list names must be unique, so include the bank select when several banks share a name
+    fn list_name(&self, banks: &[Bank]) -> String {
+        if banks.iter().filter(|b| b.name == self.name).count() > 1 {
+            let bank_select: Vec<String> = [("MSB", self.msb), ("LSB", self.lsb)].iter()
+                .filter_map(|(name, value)| value.map(|value| format!("{} {}", name, value)))
+                .collect();
+            if bank_select.is_empty() {
+                format!("{} (no bank select)", self.name)
+            } else {
+                format!("{} ({})", self.name, bank_select.join(", "))
+            }
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+/// Write the patches as a MIDI Name Document, with a patch bank for each group (or bank select),
+/// returning the number of patches skipped because they have no program change
+pub fn export(file: &str, patches: &[Patch], manufacturer: &str, model: &str) -> Result<usize, Box<dyn Error>> {
+    let mut banks: Vec<Bank> = Vec::new();
+    let mut skipped = 0;
+    for patch in patches {
+        if patch.program().is_none() {
+            skipped += 1;
+            continue;
+        }
+        let name = match patch.group() {
+            Some(group) => group.to_string(),
+            None => match (patch.bank_msb(), patch.bank_lsb()) {
+                (None, None) => String::from("Patches"),
+                (msb, lsb) => format!("Bank {}/{}", msb.unwrap_or(0), lsb.unwrap_or(0))
+            }
+        };
+        match banks.iter_mut().find(|b| b.name == name && b.msb == patch.bank_msb() && b.lsb == patch.bank_lsb()) {
+            Some(bank) => bank.patches.push(patch),
+            None => banks.push(Bank {
+                name,
+                msb: patch.bank_msb(),
+                lsb: patch.bank_lsb(),
+                patches: vec![patch]
+            })
+        }
+    }
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(DOCTYPE);
+    xml.push_str("\n<MIDINameDocument>\n");
+    xml.push_str("  <Author>midi-patch-changer</Author>\n");
+    xml.push_str("  <MasterDeviceNames>\n");
+    xml.push_str(&format!("    <Manufacturer>{}</Manufacturer>\n", escape(manufacturer)));
+    xml.push_str(&format!("    <Model>{}</Model>\n", escape(model)));
+    xml.push_str("    <CustomDeviceMode Name=\"Default\">\n");
+    xml.push_str("      <ChannelNameSetAssignments>\n");
+    for channel in 1..=16 {
+        xml.push_str(&format!("        <ChannelNameSetAssign Channel=\"{}\" NameSet=\"Patches\"/>\n", channel));
+    }
+    xml.push_str("      </ChannelNameSetAssignments>\n");
+    xml.push_str("    </CustomDeviceMode>\n");
+    xml.push_str("    <ChannelNameSet Name=\"Patches\">\n");
+    xml.push_str("      <AvailableForChannels>\n");
+    for channel in 1..=16 {
+        xml.push_str(&format!("        <AvailableChannel Channel=\"{}\" Available=\"true\"/>\n", channel));
+    }
+    xml.push_str("      </AvailableForChannels>\n");
+    for bank in &banks {
+        xml.push_str(&format!("      <PatchBank Name=\"{}\">\n", escape(&bank.name)));
+        if bank.msb.is_some() || bank.lsb.is_some() {
+            xml.push_str("        <MIDICommands>\n");
+            if let Some(msb) = bank.msb {
+                xml.push_str(&format!("          <ControlChange Control=\"0\" Value=\"{}\"/>\n", msb));
+            }
+            if let Some(lsb) = bank.lsb {
+                xml.push_str(&format!("          <ControlChange Control=\"32\" Value=\"{}\"/>\n", lsb));
+            }
+            xml.push_str("        </MIDICommands>\n");
+        }
+        xml.push_str(&format!("        <PatchNameList Name=\"{}\">\n", escape(&bank.list_name(&banks))));
+        for (number, patch) in bank.patches.iter().enumerate() {
+            xml.push_str(&format!("          <Patch Number=\"{}\" Name=\"{}\" ProgramChange=\"{}\"/>\n", number + 1, escape(&patch.name), patch.program().unwrap_or(0)));
+        }
+        xml.push_str("        </PatchNameList>\n");
+        xml.push_str("      </PatchBank>\n");
+    }
+    xml.push_str("    </ChannelNameSet>\n");
+    xml.push_str("  </MasterDeviceNames>\n");
+    xml.push_str("</MIDINameDocument>\n");
+    fs::write(file, xml).map_err(|e| format!("Cannot write to '{}': {}", file, e))?;
+    Ok(skipped)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
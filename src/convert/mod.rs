@@ -0,0 +1,3 @@
+pub mod ins;
+pub mod midnam;
+pub mod csv;
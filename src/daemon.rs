@@ -0,0 +1,117 @@
+use crate::midi;
+use crate::control::Remote;
+use std::env;
+use std::io::{self, Write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Patch changes from footswitches wait for the next status check
+const STATUS_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Set by SIGTERM or SIGINT
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Run the device without a terminal or display, logging to stdout until SIGTERM or SIGINT.
+/// Patches are changed by MIDI triggers, control commands, OSC or the remote control page.
+pub fn run(mut device: midi::ThruDevice, remotes: &[Box<dyn Remote>]) {
+    let handler = request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+    let systemd = Systemd::from_env();
+    let watchdog = systemd.as_ref().and_then(Systemd::watchdog_interval);
+    let mut last_watchdog = Instant::now();
+    log(&describe_patch(&device));
+    if let Some(systemd) = &systemd {
+        systemd.notify(&format!("READY=1\nSTATUS={}", describe_patch(&device)));
+    }
+    while !STOP_REQUESTED.load(Ordering::Relaxed) {
+        let mut patch_changed = false;
+        for status in device.check_status() {
+            patch_changed |= matches!(status, midi::DeviceStatus::PatchChanged(..));
+            log(&status.to_string());
+        }
+        for remote in remotes {
+            let handled = remote.handle_requests(Some(&mut device));
+            patch_changed |= !handled.is_empty();
+            for line in handled {
+                log(&line);
+            }
+        }
+        if let Some(systemd) = &systemd {
+            if patch_changed {
+                systemd.notify(&format!("STATUS={}", describe_patch(&device)));
+            }
+            // only reassure the watchdog while statuses are still being handled
+            if let Some(interval) = watchdog.filter(|interval| last_watchdog.elapsed() >= *interval) {
+                systemd.notify("WATCHDOG=1");
+                last_watchdog += interval;
+            }
+        }
+        thread::sleep(STATUS_INTERVAL);
+    }
+    log("Stopping");
+    if let Some(systemd) = &systemd {
+        systemd.notify("STOPPING=1");
+    }
+    // dropping the device closes the MIDI devices before exiting
+    drop(device);
+}
+
+fn describe_patch(device: &midi::ThruDevice) -> String {
+    match device.current_patch() {
+        Some((number, patch)) => format!("Patch #{} {}", number, patch.name),
+        None => String::from("No patches")
+    }
+}
+
+/// Print a line straight away, as stdout is not a terminal when running as a service
+fn log(line: &str) {
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", line);
+    let _ = stdout.flush();
+}
+
+/// The socket systemd listens on for notifications, when run as a Type=notify service
+struct Systemd {
+    socket: UnixDatagram,
+    address: SocketAddr
+}
+
+impl Systemd {
+    fn from_env() -> Option<Self> {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()).ok()?,
+            None => SocketAddr::from_pathname(&path).ok()?
+        };
+        let socket = UnixDatagram::unbound().ok()?;
+        Some(Self { socket, address })
+    }
+
+    /// How often to notify the watchdog (half its timeout), if it is enabled for this process
+    fn watchdog_interval(&self) -> Option<Duration> {
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse::<u32>().ok() != Some(std::process::id()) {
+                return None;
+            }
+        }
+        let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+        Some(Duration::from_micros(usec) / 2)
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            log(&format!("Cannot notify systemd: {}", e));
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::midi;
+use crate::config::{Selection, Settings};
+use crate::control::Remote;
+use super::devicepicker::{self, DevicePicker, DeviceResult};
+use super::patchsystem::{self, PatchSystem};
+
+use iced::{Application, Command, Theme, Element};
+use iced::executor;
+use iced::Subscription;
+use iced::time;
+use std::time::Duration;
+use iced_native::window;
+use iced::window::set_mode;
+
+/// Size assumed until the first resize event arrives
+const INITIAL_SIZE: (u32, u32) = (100, 100);
+/// How often commands from other programs are checked for
+const CONTROL_POLL: Duration = Duration::from_millis(50);
+
+/// The device picker & patch system in one window, so the user can go back and forth between them
+pub struct App {
+    screen: Screen,
+    options: midi::DeviceOptions,
+    scan_devices: fn() -> Vec<String>,
+    patch_options: Vec<String>,
+    /// The devices & patch file in use, to preselect when changing devices
+    current: Option<Selection>,
+    settings: Settings,
+    remotes: Vec<Box<dyn Remote>>,
+    exit: bool
+}
+
+enum Screen {
+    Picker(DevicePicker),
+    Patches(PatchSystem)
+}
+
+pub struct Flags {
+    /// Start in the patch system with this device (opened from this selection), rather than the device picker
+    pub device: Option<(midi::ThruDevice, Selection)>,
+    pub options: midi::DeviceOptions,
+    pub scan_devices: fn() -> Vec<String>,
+    pub patch_options: Vec<String>,
+    /// Seconds to wait before starting automatically, if everything in the last selection is available
+    pub auto_start: Option<u32>,
+    /// Listen for commands to the patch system from other programs
+    pub remotes: Vec<Box<dyn Remote>>,
+    pub settings: Settings
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Picker(devicepicker::Message),
+    Patches(patchsystem::Message),
+    Control
+}
+
+impl Application for App {
+    type Message = Message;
+    type Executor = executor::Default;
+    type Flags = Flags;
+    type Theme = Theme;
+
+    fn new(flags: Self::Flags) -> (Self, Command<Message>) {
+        let mut current = None;
+        let screen = match flags.device {
+            Some((device, selection)) => {
+                current = Some(selection);
+                Screen::Patches(PatchSystem::new(device, INITIAL_SIZE, &flags.settings))
+            },
+            None => Screen::Picker(DevicePicker::new(devicepicker::Flags {
+                scan_devices: flags.scan_devices,
+                patch_options: flags.patch_options.clone(),
+                channel_base: flags.options.channel_base,
+                aliases: flags.options.aliases.clone(),
+                last_selection: Selection::load().or_else(|| default_selection(&flags.settings, &flags.patch_options)),
+                auto_start: flags.auto_start,
+                text_size: flags.settings.text_size
+            }, INITIAL_SIZE))
+        };
+        let mode = if flags.settings.fullscreen { window::Mode::Fullscreen } else { window::Mode::Windowed };
+        (Self {
+            screen,
+            options: flags.options,
+            scan_devices: flags.scan_devices,
+            patch_options: flags.patch_options,
+            current,
+            settings: flags.settings,
+            remotes: flags.remotes,
+            exit: false
+        }, set_mode(mode))
+    }
+
+    fn title(&self) -> String {
+        match &self.screen {
+            Screen::Picker(picker) => picker.title(),
+            Screen::Patches(patches) => patches.title()
+        }
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        match &self.screen {
+            Screen::Picker(picker) => picker.view().map(Message::Picker),
+            Screen::Patches(patches) => patches.view().map(Message::Patches)
+        }
+    }
+
+    fn should_exit(&self) -> bool {
+        self.exit
+    }
+
+    fn subscription(&self) -> Subscription<Message> {
+        let screen = match &self.screen {
+            Screen::Picker(picker) => picker.subscription().map(Message::Picker),
+            Screen::Patches(patches) => patches.subscription().map(Message::Patches)
+        };
+        if self.remotes.is_empty() {
+            screen
+        } else {
+            Subscription::batch([screen, time::every(CONTROL_POLL).map(|_| Message::Control)])
+        }
+    }
+
+    fn update(&mut self, message: Message) -> Command<Message> {
+        match (&mut self.screen, message) {
+            (Screen::Picker(picker), Message::Picker(message)) => match picker.update(message) {
+                Some(devicepicker::Outcome::Start(result)) => self.start(result),
+                Some(devicepicker::Outcome::Quit) => self.exit = true,
+                None => {}
+            },
+            (Screen::Patches(patches), Message::Patches(message)) => match patches.update(message) {
+                Some(patchsystem::Outcome::ChangeDevices) => self.change_devices(),
+                Some(patchsystem::Outcome::Quit) => self.exit = true,
+                None => {}
+            },
+            (screen, Message::Control) => {
+                let mut device = match screen {
+                    Screen::Patches(patches) => Some(patches.device()),
+                    Screen::Picker(_) => None
+                };
+                for remote in &self.remotes {
+                    remote.handle_requests(device.as_deref_mut());
+                }
+            },
+            _ => {} // left over from the previous screen
+        }
+        Command::none()
+    }
+}
+
+impl App {
+    fn screen_size(&self) -> (u32, u32) {
+        match &self.screen {
+            Screen::Picker(picker) => picker.screen_size(),
+            Screen::Patches(patches) => patches.screen_size()
+        }
+    }
+
+    /// Open the chosen devices in the patch system, or stay in the picker showing why they could not be opened
+    fn start(&mut self, result: DeviceResult) {
+        let device = midi::ThruDevice::new(result.midi_in.as_deref(), &result.midi_out, result.patch_file.as_deref(), self.options.clone());
+        match device {
+            Ok(device) => {
+                let selection = Selection {
+                    midi_in: result.midi_in,
+                    midi_out: Some(result.midi_out),
+                    patch_file: result.patch_file
+                };
+                if let Err(e) = selection.save() {
+                    println!("Cannot remember selection: {}", e);
+                }
+                self.current = Some(selection);
+                self.screen = Screen::Patches(PatchSystem::new(device, self.screen_size(), &self.settings));
+            },
+            Err(e) => if let Screen::Picker(picker) = &mut self.screen {
+                picker.show_error(e.to_string());
+            }
+        }
+    }
+
+    /// Close the current devices and go back to the picker
+    fn change_devices(&mut self) {
+        let picker = DevicePicker::new(devicepicker::Flags {
+            scan_devices: self.scan_devices,
+            patch_options: self.patch_options.clone(),
+            channel_base: self.options.channel_base,
+            aliases: self.options.aliases.clone(),
+            last_selection: self.current.clone().or_else(Selection::load).or_else(|| default_selection(&self.settings, &self.patch_options)),
+            auto_start: None,
+            text_size: self.settings.text_size
+        }, self.screen_size());
+        // replacing the patch system drops its device, releasing the MIDI devices for the picker to choose again
+        self.screen = Screen::Picker(picker);
+    }
+}
+
+/// The default devices from the settings, to preselect before anything has been chosen in the picker
+fn default_selection(settings: &Settings, patch_options: &[String]) -> Option<Selection> {
+    settings.midi_out.as_ref().map(|midi_out| Selection {
+        midi_in: settings.midi_in.clone(),
+        midi_out: Some(midi_out.clone()),
+        patch_file: patch_options.first().cloned()
+    })
+}
@@ -1,10 +1,23 @@
-use iced::widget::{button, row, column, text, pick_list};
-use iced::{Application, Command, Theme, Element, Alignment};
-use iced::executor;
+use iced::widget::{button, row, column, container, text, pick_list};
+use iced::{theme, Element, Alignment, Color};
 use iced::Subscription;
-use iced_native::{window, Event, Length, alignment};
-use iced::window::set_mode;
-use std::sync::mpsc;
+use iced_native::{window, mouse, touch, Event, Length, alignment};
+use iced::time;
+use std::time::Duration;
+use crate::config::Selection;
+use crate::midi;
+
+/// How often to look for MIDI devices being plugged in or removed
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the input activity light is redrawn
+const ACTIVITY_REFRESH: Duration = Duration::from_millis(100);
+/// How long the input activity light stays on after something is received
+const ACTIVITY_TIME: Duration = Duration::from_millis(300);
+const ACTIVE_COLOR: Color = Color::from_rgb(0.0, 0.8, 0.0);
+const IDLE_COLOR: Color = Color::from_rgb(0.5, 0.5, 0.5);
+const ERROR_COLOR: Color = Color::from_rgb(0.8, 0.0, 0.0);
+/// Number of patch names shown in the preview of the selected patch file
+const PREVIEW_COUNT: usize = 3;
 
 pub struct DevicePicker {
     midi_in_options: Vec<String>,
@@ -13,100 +26,189 @@ pub struct DevicePicker {
     midi_out: String,
     patch_options: Vec<String>,
     patch_file: String,
-    exit: bool,
+    /// The patches in the selected file, or why it cannot be loaded
+    patches: Result<Vec<midi::Patch>, String>,
     screen_width: u32,
     screen_height: u32,
-    result_sender: mpsc::Sender<DeviceResult>,
-    last_error: String
+    last_error: String,
+    scan_devices: fn() -> Vec<String>,
+    channel_base: midi::ChannelBase,
+    aliases: midi::DeviceAliases,
+    /// The selected MIDI IN device being watched for activity
+    input_activity: Option<(String, midi::InputActivity)>,
+    last_selection: Option<Selection>,
+    /// Seconds to count down once the last selection is available, until the user interacts
+    auto_start: Option<u32>,
+    /// Seconds until starting automatically, cancelled by touching the screen
+    countdown: Option<u32>,
+    text_size: Option<u16>
 }
 
 pub struct Flags {
-    pub midi_options: Vec<String>,
+    /// Lists the MIDI devices currently plugged in
+    pub scan_devices: fn() -> Vec<String>,
     pub patch_options: Vec<String>,
-    pub result_sender: mpsc::Sender<DeviceResult>
+    /// How channels are counted in the patch files, for sending a test patch
+    pub channel_base: midi::ChannelBase,
+    /// Listed instead of the paths of the devices they name
+    pub aliases: midi::DeviceAliases,
+    pub last_selection: Option<Selection>,
+    /// Seconds to wait before starting automatically, if everything in the last selection is available
+    pub auto_start: Option<u32>,
+    /// Overrides the text size scaled to the screen
+    pub text_size: Option<u16>
 }
 
 pub struct DeviceResult {
     pub midi_in: Option<String>,
     pub midi_out: String,
-    pub patch_file: Option<String>,
+    pub patch_file: Option<String>
+}
+
+/// What the user has chosen to do next
+pub enum Outcome {
+    Start(DeviceResult),
+    Quit
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     Start,
+    Test,
     Quit,
     PatchFileChanged(String),
     MidiInChanged(String),
     MidiOutChanged(String),
+    Tick,
+    Rescan,
+    RefreshActivity,
     EventOccurred(iced_native::Event)
 }
 
-impl Application for DevicePicker {
-    type Message = Message;
-    type Executor = executor::Default;
-    type Flags = Flags;
-    type Theme = Theme;
-
-    fn new(flags: Self::Flags) -> (Self, Command<Message>) {
-        let (midi_in, midi_out) = match flags.midi_options.len() {
-            0 => panic!("No MIDI devices found."),
-            1 => (String::new(), flags.midi_options[0].clone()),
-            _ => (flags.midi_options[0].clone(), flags.midi_options[1].clone())
-        };
-        let patch_file = match flags.patch_options.len() {
+impl DevicePicker {
+    pub fn new(flags: Flags, (screen_width, screen_height): (u32, u32)) -> Self {
+        let mut patch_file = match flags.patch_options.len() {
             0 => String::new(),
             _ => flags.patch_options[0].clone()
         };
-        let mut midi_in_options = flags.midi_options.clone();
-        midi_in_options.insert(0, String::new());
+        if let Some(saved) = flags.last_selection.as_ref().map(|last| last.patch_file.clone()) {
+            match saved {
+                Some(saved) if flags.patch_options.contains(&saved) => patch_file = saved,
+                Some(_) => {},
+                None => patch_file.clear()
+            }
+        }
         let mut patch_options = flags.patch_options;
         patch_options.insert(0, String::new());
-        (Self {
-            midi_in_options,
-            midi_out_options: flags.midi_options,
+        let mut picker = Self {
+            midi_in_options: Vec::new(),
+            midi_out_options: Vec::new(),
             patch_options,
-            result_sender: flags.result_sender,
-            midi_in,
-            midi_out,
+            midi_in: String::new(),
+            midi_out: String::new(),
             patch_file,
-            exit: false,
-            screen_width: 100,
-            screen_height: 100,
-            last_error: String::new()
-        }, set_mode(window::Mode::Fullscreen))
+            patches: Ok(Vec::new()),
+            screen_width,
+            screen_height,
+            last_error: String::new(),
+            scan_devices: flags.scan_devices,
+            channel_base: flags.channel_base,
+            aliases: flags.aliases,
+            input_activity: None,
+            last_selection: flags.last_selection,
+            auto_start: flags.auto_start,
+            countdown: None,
+            text_size: flags.text_size
+        };
+        picker.load_patches();
+        picker.update_devices((picker.scan_devices)());
+        picker
     }
 
-    fn title(&self) -> String {
+    pub fn title(&self) -> String {
         String::from("MIDI Device Picker")
     }
 
-    fn subscription(&self) -> Subscription<Message> {
-        iced::subscription::events().map(Message::EventOccurred)
+    pub fn screen_size(&self) -> (u32, u32) {
+        (self.screen_width, self.screen_height)
+    }
+
+    /// Show why the chosen devices could not be started
+    pub fn show_error(&mut self, error: String) {
+        self.last_error = error;
+        self.watch_input();
     }
 
-    fn view(&self) -> Element<Message> {
+    pub fn subscription(&self) -> Subscription<Message> {
+        let events = iced::subscription::events().map(Message::EventOccurred);
+        let rescan = time::every(RESCAN_INTERVAL).map(|_| Message::Rescan);
+        let mut subscriptions = vec![events, rescan];
+        if self.countdown.is_some() {
+            subscriptions.push(time::every(Duration::from_secs(1)).map(|_| Message::Tick));
+        }
+        if self.input_activity.is_some() {
+            subscriptions.push(time::every(ACTIVITY_REFRESH).map(|_| Message::RefreshActivity));
+        }
+        Subscription::batch(subscriptions)
+    }
+
+    pub fn view(&self) -> Element<'_, Message> {
         let height = (self.screen_height / 4) as u16;
-        let size = height / 3;
+        let size = self.text_size.unwrap_or(height / 3);
+        let start_label = match self.countdown {
+            Some(seconds) => format!("Start ({})", seconds),
+            None => String::from("Start")
+        };
+        let devices: Element<'_, Message> = if self.midi_out_options.is_empty() {
+            centred_text("Waiting for MIDI devices...", size)
+        } else {
+            column![
+                row![
+                    // lights up while anything is received from the selected device
+                    text("MIDI IN: ").size(size).height(Length::Fill).vertical_alignment(alignment::Vertical::Center).style(self.input_color()),
+                    pick_list(&self.midi_in_options, Some(self.midi_in.clone()), Message::MidiInChanged)
+                        .text_size(size).width(Length::Fill)
+                ].height(Length::Fill),
+                row![
+                    text("MIDI OUT: ").size(size).height(Length::Fill).vertical_alignment(alignment::Vertical::Center),
+                    pick_list(&self.midi_out_options, Some(self.midi_out.clone()), Message::MidiOutChanged)
+                        .text_size(size).width(Length::Fill)
+                ].height(Length::Fill)
+            ].spacing(10)
+            .into()
+        };
+        let devices: Element<'_, Message> = if self.last_error.is_empty() {
+            devices
+        } else {
+            column![
+                text(&self.last_error).size(size / 2),
+                devices
+            ].spacing(10)
+            .into()
+        };
+        let mut start = button(centred_text(&start_label, size)).width(Length::Fill);
+        if self.can_start() {
+            start = start.on_press(Message::Start);
+        }
+        let preview = match &self.patches {
+            Ok(_) if self.patch_file.is_empty() => text("No patch file, patches can only be sent from MIDI IN"),
+            Ok(patches) => text(patch_preview(patches)),
+            Err(e) => text(e).style(ERROR_COLOR)
+        };
         column![
-            row![
-                text("MIDI IN: ").size(size).height(Length::Fill).vertical_alignment(alignment::Vertical::Center),
-                pick_list(&self.midi_in_options, Some(self.midi_in.clone()), Message::MidiInChanged)
-                    .text_size(size).width(Length::Fill)
-            ].height(Length::Fill),
-            row![
-                text("MIDI OUT: ").size(size).height(Length::Fill).vertical_alignment(alignment::Vertical::Center),
-                pick_list(&self.midi_out_options, Some(self.midi_out.clone()), Message::MidiOutChanged)
-                    .text_size(size).width(Length::Fill)
+            container(devices).height(Length::FillPortion(2)).width(Length::Fill).center_x().center_y(),
+            column![
+                row![
+                    text("Patches: ").size(size).height(Length::Fill).vertical_alignment(alignment::Vertical::Center),
+                    pick_list(&self.patch_options, Some(self.patch_file.clone()), Message::PatchFileChanged)
+                        .text_size(size).width(Length::Fill)
+                ].height(Length::Fill),
+                preview.size(size / 2)
             ].height(Length::Fill),
             row![
-                text("Patches: ").size(size).height(Length::Fill).vertical_alignment(alignment::Vertical::Center),
-                pick_list(&self.patch_options, Some(self.patch_file.clone()), Message::PatchFileChanged)
-                    .text_size(size).width(Length::Fill)
-            ].height(Length::Fill),
-            row![
-                button(centred_text("Start", size))
-                    .on_press(Message::Start)
+                start,
+                button(centred_text("Test", size))
+                    .on_press(Message::Test)
                     .width(Length::Fill),
                 button(centred_text("Quit", size))
                     .on_press(Message::Quit)
@@ -118,49 +220,183 @@ impl Application for DevicePicker {
         .into()
     }
 
-    fn should_exit(&self) -> bool {
-        self.exit
-    }
-
-    fn update(&mut self, message: Message) -> iced::Command<Message> {
+    pub fn update(&mut self, message: Message) -> Option<Outcome> {
         match message {
-            Message::EventOccurred(event) => {
-                if let Event::Window(window::Event::Resized { width, height }) = event {
+            Message::EventOccurred(event) => match event {
+                Event::Window(window::Event::Resized { width, height }) => {
                     self.screen_width = width;
                     self.screen_height = height;
-                }
+                },
+                // any interaction means the user wants to choose for themselves
+                Event::Mouse(mouse::Event::ButtonPressed(_)) | Event::Touch(touch::Event::FingerPressed { .. }) => {
+                    self.auto_start = None;
+                    self.countdown = None;
+                },
+                _ => {}
             },
-            Message::Start => {
-                if !self.exit {
-                    let result = DeviceResult {
-                        midi_in: non_empty(self.midi_in.clone()),
-                        midi_out: self.midi_out.clone(),
-                        patch_file: non_empty(self.patch_file.clone())
-                    };
-                    if let Err(e) = self.result_sender.send(result) {
-                        self.last_error = format!("{}", e);
-                    } else {
-                        self.exit = true;
-                    }
-                }
+            Message::Tick => match self.countdown {
+                Some(0) | Some(1) => {
+                    self.countdown = None;
+                    return self.start();
+                },
+                Some(seconds) => self.countdown = Some(seconds - 1),
+                None => {}
             },
-            Message::Quit => self.exit = true,
+            Message::Rescan => self.update_devices((self.scan_devices)()),
+            Message::RefreshActivity => {},
+            Message::Test => self.send_test(),
+            Message::Start => return self.start(),
+            Message::Quit => return Some(Outcome::Quit),
             Message::MidiInChanged(midi_in) => {
                 if midi_in != self.midi_out {
                     self.midi_in = midi_in;
+                    self.watch_input();
                 }
             },
             Message::MidiOutChanged(midi_out) => {
                 self.midi_out = midi_out;
                 if self.midi_in == self.midi_out {
                     self.midi_in = String::new();
+                    self.watch_input();
                 }
             },
             Message::PatchFileChanged(patch_file) => {
                 self.patch_file = patch_file;
+                self.load_patches();
+            }
+        }
+        None
+    }
+
+    /// Refresh the device lists, keeping the current selections where those devices are still plugged in
+    fn update_devices(&mut self, devices: Vec<String>) {
+        let devices = self.aliases.label(devices);
+        if devices == self.midi_out_options {
+            return;
+        }
+        let was_waiting = self.midi_out_options.is_empty();
+        self.midi_in_options = devices.clone();
+        self.midi_in_options.insert(0, String::new());
+        self.midi_out_options = devices;
+        if was_waiting || !self.midi_out_options.contains(&self.midi_out) {
+            self.select_default_devices();
+        } else if !self.midi_in_options.contains(&self.midi_in) {
+            self.midi_in = String::new();
+        }
+        self.watch_input();
+        // start counting down once everything chosen last time is plugged in, and stop if some of it is removed
+        if !self.last_selection_available() {
+            self.countdown = None;
+        } else if self.countdown.is_none() {
+            self.countdown = self.auto_start;
+        }
+    }
+
+    /// Select the devices chosen last time if they are plugged in, otherwise the first devices listed
+    fn select_default_devices(&mut self) {
+        let options = &self.midi_out_options;
+        (self.midi_in, self.midi_out) = match options.len() {
+            0 => (String::new(), String::new()),
+            1 => (String::new(), options[0].clone()),
+            _ => (options[0].clone(), options[1].clone())
+        };
+        if let Some(last) = &self.last_selection {
+            if let Some(midi_out) = last.midi_out.as_ref().filter(|out| options.contains(out)) {
+                self.midi_out = midi_out.clone();
             }
+            match &last.midi_in {
+                Some(midi_in) if options.contains(midi_in) => self.midi_in = midi_in.clone(),
+                Some(_) => {},
+                None => self.midi_in = String::new()
+            }
+        }
+        if self.midi_in == self.midi_out {
+            self.midi_in = String::new();
         }
-        Command::none()
+    }
+
+    /// True if everything in the last selection is available and still selected
+    fn last_selection_available(&self) -> bool {
+        match &self.last_selection {
+            Some(last) => last.midi_out.as_ref() == Some(&self.midi_out)
+                && last.midi_in.clone().unwrap_or_default() == self.midi_in
+                && last.patch_file.clone().unwrap_or_default() == self.patch_file,
+            None => false
+        }
+    }
+
+    /// Watch the selected MIDI IN device for activity, if it isn't already being watched
+    fn watch_input(&mut self) {
+        if self.input_activity.as_ref().map(|(path, _)| path) == Some(&self.midi_in) {
+            return;
+        }
+        // release the previous device before opening another
+        self.input_activity = None;
+        if let Some(path) = non_empty(self.midi_in.clone()).and_then(|midi_in| self.aliases.resolve(&midi_in)) {
+            match midi::InputActivity::watch(&path) {
+                Ok(activity) => self.input_activity = Some((self.midi_in.clone(), activity)),
+                Err(e) => self.last_error = e
+            }
+        }
+    }
+
+    fn input_color(&self) -> theme::Text {
+        match &self.input_activity {
+            Some((_, activity)) if activity.is_active(ACTIVITY_TIME) => theme::Text::Color(ACTIVE_COLOR),
+            Some(_) => theme::Text::Color(IDLE_COLOR),
+            None => theme::Text::Default
+        }
+    }
+
+    /// Send the first patch of the selected patch file (if any) and a short note to the selected MIDI OUT device
+    fn send_test(&mut self) {
+        if self.midi_out.is_empty() {
+            return;
+        }
+        let first_patch = self.patches.as_ref().ok().and_then(|patches| patches.first());
+        self.last_error = match self.aliases.resolve(&self.midi_out).map(|path| midi::send_test(&path, first_patch)) {
+            Some(Ok(())) => String::new(),
+            Some(Err(e)) => e.to_string(),
+            None => format!("'{}' is not plugged in", self.midi_out)
+        };
+    }
+
+    /// Parse the selected patch file straight away, so any mistakes are shown before starting
+    fn load_patches(&mut self) {
+        self.patches = if self.patch_file.is_empty() {
+            Ok(Vec::new())
+        } else {
+            midi::load_patches(&self.patch_file, self.channel_base).map_err(|e| e.to_string())
+        };
+    }
+
+    fn can_start(&self) -> bool {
+        !self.midi_out.is_empty() && self.patches.is_ok()
+    }
+
+    fn start(&mut self) -> Option<Outcome> {
+        if !self.can_start() {
+            return None;
+        }
+        self.last_error = String::new();
+        // the patch system needs to open the MIDI IN device itself
+        self.input_activity = None;
+        Some(Outcome::Start(DeviceResult {
+            midi_in: non_empty(self.midi_in.clone()),
+            midi_out: self.midi_out.clone(),
+            patch_file: non_empty(self.patch_file.clone())
+        }))
+    }
+}
+
+/// The number of patches followed by the first few names
+fn patch_preview(patches: &[midi::Patch]) -> String {
+    let names: Vec<&str> = patches.iter().take(PREVIEW_COUNT).map(|patch| patch.name.as_str()).collect();
+    let more = if patches.len() > PREVIEW_COUNT { ", ..." } else { "" };
+    match patches.len() {
+        0 => String::from("No patches in this file"),
+        1 => format!("1 patch: {}", names[0]),
+        count => format!("{} patches: {}{}", count, names.join(", "), more)
     }
 }
 
@@ -172,6 +408,6 @@ fn non_empty(s: String) -> Option<String> {
     }
 }
 
-fn centred_text<T>(s: &str, size: u16) -> Element<T> {
+fn centred_text<'a, T>(s: &str, size: u16) -> Element<'a, T> {
     text(s).size(size).horizontal_alignment(alignment::Horizontal::Center).vertical_alignment(alignment::Vertical::Center).into()
 }
\ No newline at end of file
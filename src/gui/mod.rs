@@ -1,5 +1,6 @@
+mod app;
 mod patchsystem;
-pub mod devicepicker;
+mod monitor;
+mod devicepicker;
 
-pub use patchsystem::PatchSystem;
-pub use devicepicker::DevicePicker;
\ No newline at end of file
+pub use app::{App, Flags};
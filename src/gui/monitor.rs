@@ -0,0 +1,98 @@
+use crate::midi::{MonitorEvent, MonitorFilter, MessageKind};
+
+use iced::widget::{button, row, column, text, pick_list, scrollable, Column};
+use iced::{Element, Alignment};
+use iced_native::{Length, alignment};
+use std::collections::VecDeque;
+use std::sync::mpsc;
+
+/// Most recent events kept for display
+const HISTORY: usize = 500;
+const ALL_TYPES: &str = "All types";
+const ALL_CHANNELS: &str = "All channels";
+
+pub struct MidiMonitor {
+    events: mpsc::Receiver<MonitorEvent>,
+    history: VecDeque<MonitorEvent>,
+    filter: MonitorFilter,
+    type_options: Vec<String>,
+    channel_options: Vec<String>
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    TypeChanged(String),
+    ChannelChanged(String),
+    Clear
+}
+
+impl MidiMonitor {
+    pub fn new(events: mpsc::Receiver<MonitorEvent>) -> Self {
+        let mut type_options: Vec<String> = MessageKind::ALL.iter().map(|k| k.to_string()).collect();
+        type_options.insert(0, String::from(ALL_TYPES));
+        let mut channel_options: Vec<String> = (1..=16).map(|ch| format!("Channel {}", ch)).collect();
+        channel_options.insert(0, String::from(ALL_CHANNELS));
+        Self {
+            events,
+            history: VecDeque::new(),
+            filter: MonitorFilter::default(),
+            type_options,
+            channel_options
+        }
+    }
+
+    /// Collect any events received since the last refresh
+    pub fn refresh(&mut self) {
+        for event in self.events.try_iter() {
+            self.history.push_back(event);
+        }
+        while self.history.len() > HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn update(&mut self, message: Message) {
+        match message {
+            Message::TypeChanged(selected) => {
+                self.filter.kind = MessageKind::ALL.iter().find(|k| k.to_string() == selected).copied();
+            },
+            Message::ChannelChanged(selected) => {
+                self.filter.channel = selected.strip_prefix("Channel ").and_then(|ch| ch.parse().ok());
+            },
+            Message::Clear => self.history.clear()
+        }
+    }
+
+    pub fn view<'a, T: 'a + Clone>(&'a self, size: u16, map: fn(Message) -> T, back: T) -> Element<'a, T> {
+        let selected_type = match self.filter.kind {
+            Some(kind) => kind.to_string(),
+            None => String::from(ALL_TYPES)
+        };
+        let selected_channel = match self.filter.channel {
+            Some(ch) => format!("Channel {}", ch),
+            None => String::from(ALL_CHANNELS)
+        };
+        let lines: Vec<Element<T>> = self.history.iter().rev()
+            .filter(|event| self.filter.matches(event))
+            .map(|event| text(event.to_string()).size(size / 2).into())
+            .collect();
+        column![
+            row![
+                pick_list(&self.type_options, Some(selected_type), move |s| map(Message::TypeChanged(s)))
+                    .text_size(size / 2).width(Length::Fill),
+                pick_list(&self.channel_options, Some(selected_channel), move |s| map(Message::ChannelChanged(s)))
+                    .text_size(size / 2).width(Length::Fill),
+                button(text("Clear").size(size / 2).horizontal_alignment(alignment::Horizontal::Center))
+                    .on_press(map(Message::Clear))
+                    .width(Length::Fill),
+                button(text("Back").size(size / 2).horizontal_alignment(alignment::Horizontal::Center))
+                    .on_press(back)
+                    .width(Length::Fill)
+            ].spacing(10).height(Length::Units(size)),
+            scrollable(Column::with_children(lines).width(Length::Fill)).height(Length::Fill)
+        ].padding(10)
+        .spacing(10)
+        .align_items(Alignment::Fill)
+        .into()
+    }
+}
@@ -1,21 +1,37 @@
 use crate::midi;
+use crate::midi::Transport;
+use crate::config::Settings;
+use super::monitor::{self, MidiMonitor};
 
 use iced::widget::{button, row, column, text};
-use iced::{Application, Command, Theme, Element, Alignment};
-use iced::executor;
+use iced::{Element, Alignment};
 use iced::Subscription;
 use iced_native::{window, mouse, Event, Length, alignment};
-use iced::window::set_mode;
 use std::time::Duration;
 use iced::time;
 
+const ERROR_DISPLAY_TIME: Duration = Duration::from_secs(10);
+/// How often device status is checked (and the monitor refreshed),
+/// often enough that MIDI triggers such as a patch change footswitch take effect straight away
+const STATUS_REFRESH: Duration = Duration::from_millis(50);
+
 pub struct PatchSystem {
     device: midi::ThruDevice,
     screen_height: u32,
     screen_width: u32,
     show_buttons: bool,
     mouse_down: bool,
-    exit: bool
+    monitor: Option<MidiMonitor>,
+    /// How long the screen must be held to show the menu
+    long_press: Duration,
+    text_size: Option<u16>,
+    patch_text_size: Option<u16>
+}
+
+/// What the user has chosen to do next
+pub enum Outcome {
+    ChangeDevices,
+    Quit
 }
 
 #[derive(Debug, Clone)]
@@ -23,44 +39,70 @@ pub enum Message {
     NextPatch,
     PreviousPatch,
     ResetPatch,
+    ChangeDevices,
     QuitApplication,
+    ToggleRecording,
+    TogglePlayback,
+    ShowMonitor,
+    HideMonitor,
+    Monitor(monitor::Message),
     MouseHeld,
+    Tick,
     EventOccurred(iced_native::Event)
 }
 
-impl Application for PatchSystem {
-    type Message = Message;
-    type Executor = executor::Default;
-    type Flags = midi::ThruDevice;
-    type Theme = Theme;
-
-    fn new(device: Self::Flags) -> (Self, Command<Message>) {
-        (Self {
+impl PatchSystem {
+    pub fn new(device: midi::ThruDevice, (screen_width, screen_height): (u32, u32), settings: &Settings) -> Self {
+        Self {
             device,
-            screen_height: 100,
-            screen_width: 100,
+            screen_height,
+            screen_width,
             show_buttons: false,
-            exit: false,
-            mouse_down: false
-        }, set_mode(window::Mode::Fullscreen))
+            mouse_down: false,
+            monitor: None,
+            long_press: Duration::from_millis(settings.long_press_ms),
+            text_size: settings.text_size,
+            patch_text_size: settings.patch_text_size
+        }
+    }
+
+    pub fn device(&mut self) -> &mut midi::ThruDevice {
+        &mut self.device
+    }
+
+    pub fn screen_size(&self) -> (u32, u32) {
+        (self.screen_width, self.screen_height)
     }
 
-    fn title(&self) -> String {
+    pub fn title(&self) -> String {
         String::from("MIDI Patch Changer") //TODO include input/output device names & patch list name?
     }
 
-    fn view(&self) -> Element<Message> {
-        let small = (self.screen_height / 10) as u16; // 1/10 of screen height
-        let big = (self.screen_height as u16 - 2 * small) / 3; // space for 3 lines of text
+    pub fn view(&self) -> Element<'_, Message> {
+        let small = self.text_size.unwrap_or((self.screen_height / 10) as u16); // 1/10 of screen height
+        let big = self.patch_text_size.unwrap_or((self.screen_height as u16).saturating_sub(2 * small) / 3); // space for 3 lines of text
+        if let Some(monitor) = &self.monitor {
+            return monitor.view(small, Message::Monitor, Message::HideMonitor);
+        }
+        let connection_warning = self.connection_warning();
         let top_text = if self.show_buttons {
             "Click to exit menu"
+        } else if let Some(warning) = &connection_warning {
+            warning
         } else {
             match self.device.previous_patch() { Some(patch) => &patch.name, None => "" }
         };
-        let top = text(top_text)
-            .size(small)
-            .height(Length::Units(small))
-            .horizontal_alignment(alignment::Horizontal::Center);
+        let top = row![
+            text(top_text)
+                .size(small)
+                .height(Length::Units(small))
+                .width(Length::Fill)
+                .horizontal_alignment(alignment::Horizontal::Center),
+            text(self.tempo_text())
+                .size(small)
+                .height(Length::Units(small))
+                .horizontal_alignment(alignment::Horizontal::Right)
+        ];
         let middle = text(match self.device.current_patch() { Some((number, patch)) => format!("#{} {}", number, patch.name), None => String::from("No Patches")})
             .size(big)
             .height(Length::Fill)
@@ -68,7 +110,7 @@ impl Application for PatchSystem {
             .horizontal_alignment(alignment::Horizontal::Center);
         let bottom = if self.show_buttons {
             let button_text = small / 2;
-            row![
+            let mut buttons = row![
                 button(text("Next Patch").size(button_text).horizontal_alignment(alignment::Horizontal::Center).vertical_alignment(alignment::Vertical::Center))
                     .on_press(Message::NextPatch)
                     .width(Length::Fill),
@@ -78,12 +120,27 @@ impl Application for PatchSystem {
                 button(text("Reset").size(button_text).horizontal_alignment(alignment::Horizontal::Center).vertical_alignment(alignment::Vertical::Center))
                     .on_press(Message::ResetPatch)
                     .width(Length::Fill),
+                button(text(if self.device.is_recording() { "Stop Recording" } else { "Record" }).size(button_text).horizontal_alignment(alignment::Horizontal::Center).vertical_alignment(alignment::Vertical::Center))
+                    .on_press(Message::ToggleRecording)
+                    .width(Length::Fill),
+                button(text("Monitor").size(button_text).horizontal_alignment(alignment::Horizontal::Center).vertical_alignment(alignment::Vertical::Center))
+                    .on_press(Message::ShowMonitor)
+                    .width(Length::Fill),
+                button(text("Devices").size(button_text).horizontal_alignment(alignment::Horizontal::Center).vertical_alignment(alignment::Vertical::Center))
+                    .on_press(Message::ChangeDevices)
+                    .width(Length::Fill),
                 button(text("QUIT").size(button_text).horizontal_alignment(alignment::Horizontal::Center).vertical_alignment(alignment::Vertical::Center))
                     .on_press(Message::QuitApplication)
                     .width(Length::Fill)
-            ]
-            .spacing(10)
-            .height(Length::Units(small))
+            ];
+            if self.device.can_play() {
+                buttons = buttons.push(button(text(if self.device.is_playing() { "Stop Playing" } else { "Play" }).size(button_text).horizontal_alignment(alignment::Horizontal::Center).vertical_alignment(alignment::Vertical::Center))
+                    .on_press(Message::TogglePlayback)
+                    .width(Length::Fill));
+            }
+            buttons
+                .spacing(10)
+                .height(Length::Units(small))
         } else {
             row![
                 text(match self.device.next_patch() { Some(patch) => &patch.name, None => ""})
@@ -99,21 +156,18 @@ impl Application for PatchSystem {
             .into()
     }
 
-    fn should_exit(&self) -> bool {
-        self.exit
-    }
-
-    fn subscription(&self) -> Subscription<Message> {
+    pub fn subscription(&self) -> Subscription<Message> {
         let events = iced::subscription::events().map(Message::EventOccurred);
+        let status = time::every(STATUS_REFRESH).map(|_| Message::Tick);
         if self.mouse_down {
-            let timeout = time::every(Duration::from_millis(1500)).map(|_| Message::MouseHeld);
-            Subscription::batch([events, timeout])
+            let timeout = time::every(self.long_press).map(|_| Message::MouseHeld);
+            Subscription::batch([events, status, timeout])
         } else {
-            events
+            Subscription::batch([events, status])
         }
     }
 
-    fn update(&mut self, message: Message) -> iced::Command<Message> {
+    pub fn update(&mut self, message: Message) -> Option<Outcome> {
         match message {
             Message::NextPatch => {
                 self.device.increment_patch(1);
@@ -124,39 +178,62 @@ impl Application for PatchSystem {
             Message::ResetPatch => {
                 self.device.set_patch(0);
             },
-            Message::QuitApplication => self.exit = true,
+            Message::ChangeDevices => return Some(Outcome::ChangeDevices),
+            Message::QuitApplication => return Some(Outcome::Quit),
+            Message::ToggleRecording => {
+                self.show_buttons = false;
+                self.device.toggle_recording();
+            },
+            Message::TogglePlayback => {
+                self.show_buttons = false;
+                self.device.toggle_playback();
+            },
+            Message::ShowMonitor => {
+                self.show_buttons = false;
+                self.monitor = Some(MidiMonitor::new(self.device.monitor()));
+            },
+            Message::HideMonitor => self.monitor = None,
+            Message::Monitor(message) => {
+                if let Some(monitor) = &mut self.monitor {
+                    monitor.update(message);
+                }
+            },
             Message::MouseHeld => {
                 if self.mouse_down {
                     self.mouse_down = false;
                     self.show_buttons = !self.show_buttons;
                 }
             },
+            Message::Tick => {
+                self.device.check_status();
+                if let Some(monitor) = &mut self.monitor {
+                    monitor.refresh();
+                }
+            },
             Message::EventOccurred(event) => {
                 match event {
                     Event::Window(window::Event::Resized { width, height }) => {
                         self.screen_width = width;
                         self.screen_height = height;
                     },
-                    Event::Mouse(mouse) => {
+                    Event::Mouse(mouse) if self.monitor.is_none() => {
                         match mouse {
                             mouse::Event::ButtonPressed(_) => {
                                 self.mouse_down = true;
                             },
-                            mouse::Event::ButtonReleased(button) => {
-                                if self.mouse_down {
-                                    self.mouse_down = false;
-                                    if self.show_buttons {
-                                        self.show_buttons = false;
-                                    } else {
-                                        match button {
-                                            mouse::Button::Left => {
-                                                self.device.increment_patch(1);
-                                            },
-                                            mouse::Button::Right => {
-                                                self.device.increment_patch(-1);
-                                            },
-                                            _ => self.show_buttons = true
-                                        }
+                            mouse::Event::ButtonReleased(button) if self.mouse_down => {
+                                self.mouse_down = false;
+                                if self.show_buttons {
+                                    self.show_buttons = false;
+                                } else {
+                                    match button {
+                                        mouse::Button::Left => {
+                                            self.device.increment_patch(1);
+                                        },
+                                        mouse::Button::Right => {
+                                            self.device.increment_patch(-1);
+                                        },
+                                        _ => self.show_buttons = true
                                     }
                                 }
                             },
@@ -167,6 +244,30 @@ impl Application for PatchSystem {
                 }
             }
         }
-        Command::none()
+        None
+    }
+
+    fn tempo_text(&self) -> String {
+        let recording = if self.device.is_recording() { "REC " } else { "" };
+        let playing = if self.device.transport() == Transport::Playing { "> " } else { "" };
+        match self.device.tempo() {
+            Some(bpm) => format!("{}{}{:.0} BPM", recording, playing, bpm),
+            None => format!("{}{}", recording, playing).trim().to_string()
+        }
+    }
+
+    fn connection_warning(&self) -> Option<String> {
+        let health = self.device.health();
+        let input = health.input_connected == Some(false);
+        let output = !health.output_connected;
+        match (input, output) {
+            (true, true) => Some(String::from("MIDI IN & OUT disconnected")),
+            (true, false) => Some(String::from("MIDI IN disconnected")),
+            (false, true) => Some(String::from("MIDI OUT disconnected")),
+            (false, false) => match &health.last_error {
+                Some((when, error)) if when.elapsed() < ERROR_DISPLAY_TIME => Some(error.to_string()),
+                _ => None
+            }
+        }
     }
 }
\ No newline at end of file
@@ -1,68 +1,219 @@
 use std::fs;
 use std::env;
 use std::error::Error;
+use std::path::Path;
+use std::net::SocketAddr;
 use iced::{Application, Settings};
-use std::sync::mpsc;
-use std::process::Command;
 
 mod midi;
+mod args;
 mod cli;
+mod daemon;
+mod control;
+mod osc;
+mod web;
+mod server;
 mod gui;
+mod convert;
+mod config;
 
 #[macro_use] extern crate serde_derive;
 
-enum Mode {
-    Cli,
-    Gui
+fn main() -> Result<(), Box<dyn Error>> {
+    let args::Invocation { command, mut options, monitor_filter, settings } = args::parse(env::args().collect())?;
+    let base = options.channel_base;
+    match command {
+        args::Command::Help => println!("{}", args::USAGE),
+        args::Command::ImportIns { ins_file, patch_file, instrument } => import_ins(&ins_file, &patch_file, instrument.as_deref(), base)?,
+        args::Command::ImportMidnam { midnam_file, patch_file, name_set } => import_midnam(&midnam_file, &patch_file, name_set.as_deref(), base)?,
+        args::Command::ExportMidnam { patch_file, midnam_file, manufacturer, model } => export_midnam(&patch_file, &midnam_file, &manufacturer, &model, base)?,
+        args::Command::Convert { from, to } => convert(&from, &to, base)?,
+        args::Command::Validate(files) => validate(&files, base)?,
+        args::Command::ListDevices => list_devices()?,
+        args::Command::Control(command) => control::send(&settings.control_socket(), &command)?,
+        args::Command::Run(mode, devices) => {
+            options.aliases = config::load_aliases()?;
+            let mut device = midi::ThruDevice::new(devices.midi_in.as_deref(), &devices.midi_out, devices.patch_file.as_deref(), options.clone())?;
+            let remotes = start_remotes(&settings);
+            match mode {
+                args::Mode::Cli => cli::run(&mut device, &settings.keys, &remotes),
+                args::Mode::Monitor => cli::monitor(&mut device, &monitor_filter, &remotes),
+                args::Mode::Daemon => daemon::run(device, &remotes),
+                args::Mode::Gui => {
+                    // when changing devices, offer the other patch files alongside this one
+                    let patch_dir = match devices.patch_file.as_deref().and_then(|file| Path::new(file).parent()) {
+                        Some(dir) if !dir.as_os_str().is_empty() => dir.display().to_string(),
+                        _ => String::from(".")
+                    };
+                    let selection = config::Selection {
+                        midi_in: devices.midi_in,
+                        midi_out: Some(devices.midi_out),
+                        patch_file: devices.patch_file
+                    };
+                    let flags = gui::Flags {
+                        device: Some((device, selection)),
+                        options,
+                        scan_devices: midi::midi_devices,
+                        patch_options: list_files(&patch_dir, "").unwrap_or_default(),
+                        auto_start: None,
+                        remotes,
+                        settings
+                    };
+                    gui::App::run(Settings::with_flags(flags)).map_err(|e| format!("PatchSystem GUI error: {}", e))?
+                }
+            }
+        },
+        args::Command::Pick { patches, auto_start } => {
+            options.aliases = config::load_aliases()?;
+            let patch_options = list_files(&patches, "").map_err(|e| format!("Cannot list patch files in '{}': {}", patches, e))?;
+            let flags = gui::Flags {
+                device: None,
+                options,
+                scan_devices: midi::midi_devices,
+                patch_options,
+                auto_start,
+                remotes: start_remotes(&settings),
+                settings
+            };
+            gui::App::run(Settings::with_flags(flags)).map_err(|e| format!("DevicePicker GUI error: {}", e))?;
+        }
+    }
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() >= 3 {
-        // cli/gui patches
-        let mode = if args[1] == "cli" {
-            Mode::Cli
-        } else if args[1] == "gui" {
-            Mode::Gui
-        } else {
-            return Err(format!("The first argument must specifiy 'cli' or 'gui'").into())
-        };
-        let midi_in = args.get(2).ok_or("The second argument should be the MIDI-IN device (or '-' for no input device)")?;
-        let midi_out = args.get(3).ok_or("The third argument should be the MIDI-OUT device")?;
-        let patch_file: Option<&str> = match args.get(4) {
-            Some(file) => Some(file),
-            None => None
-        };
-        let mut device = midi::ThruDevice::new(if midi_in == "-" { None } else { Some(midi_in) }, midi_out, patch_file)?;
-        match mode {
-            Mode::Cli => cli::run(&mut device),
-            Mode::Gui => gui::PatchSystem::run(Settings::with_flags(device)).map_err(|e| format!("PatchSystem GUI error: {}", e))?
+/// Listen for commands from other programs as enabled in the settings,
+/// carrying on without any which cannot be started
+fn start_remotes(settings: &config::Settings) -> Vec<Box<dyn control::Remote>> {
+    let mut remotes: Vec<Box<dyn control::Remote>> = Vec::new();
+    if settings.control {
+        match control::ControlServer::start(&settings.control_socket()) {
+            Ok(server) => {
+                println!("Listening for control commands on '{}'", server.path().display());
+                remotes.push(Box::new(server));
+            },
+            Err(e) => println!("Control commands are disabled: {}", e)
+        }
+    }
+    if let Some(port) = settings.osc_port {
+        // listen on every interface, for tablets & lighting desks on the local network
+        match osc::OscServer::start(SocketAddr::from(([0, 0, 0, 0], port))) {
+            Ok(server) => {
+                println!("Listening for OSC on port {}", port);
+                remotes.push(Box::new(server));
+            },
+            Err(e) => println!("OSC is disabled: {}", e)
+        }
+    }
+    if let Some(port) = settings.web_port {
+        match web::WebServer::start(port) {
+            Ok(server) => {
+                println!("Serving the remote control page on port {}", port);
+                remotes.push(Box::new(server));
+            },
+            Err(e) => println!("The remote control page is disabled: {}", e)
         }
+    }
+    remotes
+}
+
+fn import_ins(ins_file: &str, patch_file: &str, instrument: Option<&str>, base: midi::ChannelBase) -> Result<(), Box<dyn Error>> {
+    let patches = convert::ins::import(ins_file, instrument)?;
+    midi::save_patches(patch_file, &patches, base)?;
+    println!("Imported {} patches to '{}'", patches.len(), patch_file);
+    Ok(())
+}
+
+fn import_midnam(midnam_file: &str, patch_file: &str, name_set: Option<&str>, base: midi::ChannelBase) -> Result<(), Box<dyn Error>> {
+    let patches = convert::midnam::import(midnam_file, name_set)?;
+    midi::save_patches(patch_file, &patches, base)?;
+    println!("Imported {} patches to '{}'", patches.len(), patch_file);
+    Ok(())
+}
+
+fn export_midnam(patch_file: &str, midnam_file: &str, manufacturer: &str, model: &str, base: midi::ChannelBase) -> Result<(), Box<dyn Error>> {
+    let patches = midi::load_patches(patch_file, base)?;
+    let skipped = convert::midnam::export(midnam_file, &patches, manufacturer, model)?;
+    println!("Exported {} patches to '{}'", patches.len() - skipped, midnam_file);
+    if skipped > 0 {
+        println!("Skipped {} patches without a program", skipped);
+    }
+    Ok(())
+}
+
+fn convert(from: &str, to: &str, base: midi::ChannelBase) -> Result<(), Box<dyn Error>> {
+    let is_csv = |file: &str| file.to_lowercase().ends_with(".csv");
+    if is_csv(from) && !is_csv(to) {
+        let patches = convert::csv::import(from, base)?;
+        midi::save_patches(to, &patches, base)?;
+        println!("Converted {} patches to '{}'", patches.len(), to);
+    } else if !is_csv(from) && is_csv(to) {
+        let patches = midi::load_patches(from, base)?;
+        convert::csv::export(to, &patches, base)?;
+        println!("Converted {} patches to '{}'", patches.len(), to);
     } else {
-        // gui picker
-        let patch_dir_or_file = match args.get(1) {
-            Some(arg) => arg,
-            None => "."
-        };
-        let (tx, rx) = mpsc::channel();
-        let flags = gui::devicepicker::Flags {
-            midi_options: list_files("/dev", "midi")?,
-            patch_options: list_files(patch_dir_or_file, "")?,
-            result_sender: tx
-        };
-        gui::DevicePicker::run(Settings::with_flags(flags)).map_err(|e| format!("DevicePicker GUI error: {}", e))?;
-        if let Ok(result) = rx.try_recv() {
-            let mut new_args: Vec<String> = vec![
-                String::from("gui"),
-                match result.midi_in { Some(midi_in) => midi_in, None => String::from("-") },
-                result.midi_out
-            ];
-            if let Some(patch_file) = result.patch_file {
-                new_args.push(patch_file);
+        return Err("Exactly one of the files to convert should be a .csv file".into());
+    }
+    Ok(())
+}
+
+fn validate(files: &[String], base: midi::ChannelBase) -> Result<(), Box<dyn Error>> {
+    let mut problem_count = 0;
+    for file in files {
+        // a file which cannot be read or parsed counts as one problem, so the rest are still checked
+        let problems = match midi::validate_patches(file, base) {
+            Ok(problems) => problems,
+            Err(e) => {
+                println!("{}", e);
+                problem_count += 1;
+                continue;
             }
-            let _command = Command::new(&args[0]).args(new_args).spawn()?;
+        };
+        if problems.is_empty() {
+            println!("'{}' is valid", file);
         } else {
-            println!("No devices selected.");
+            println!("'{}' has {} problems:", file, problems.len());
+            for problem in &problems {
+                println!("  {}", problem);
+            }
+        }
+        problem_count += problems.len();
+    }
+    if problem_count > 0 {
+        return Err(format!("Found {} problems", problem_count).into());
+    }
+    Ok(())
+}
+
+/// Print every MIDI device with what identifies it, for writing device aliases
+fn list_devices() -> Result<(), Box<dyn Error>> {
+    let aliases = config::load_aliases()?;
+    let devices = midi::midi_devices();
+    if devices.is_empty() {
+        println!("No MIDI devices are plugged in");
+    }
+    for path in devices {
+        let info = midi::DeviceInfo::identify(&path);
+        let mut details = Vec::new();
+        if let Some(usb) = info.usb {
+            details.push(format!("usb {}", usb));
+        }
+        if let Some(serial) = info.serial {
+            details.push(format!("serial {}", serial));
+        }
+        if let Some(card) = info.card {
+            details.push(format!("card {}", card));
+        }
+        if details.is_empty() {
+            details.push(String::from("not a USB or ALSA device"));
+        }
+        if let Some(name) = aliases.name_of(&path) {
+            details.push(format!("alias '{}'", name));
+        }
+        println!("{}: {}", path, details.join(", "));
+    }
+    if aliases.is_empty() {
+        if let Some(file) = config::aliases_file() {
+            println!("No device aliases are defined in '{}'", file.display());
         }
     }
     Ok(())
@@ -83,4 +234,4 @@ fn list_files(root: &str, prefix: &str) -> Result<Vec<String>, Box<dyn Error>> {
     } else {
         Ok(vec![root.to_string()])
     }
-}
\ No newline at end of file
+}
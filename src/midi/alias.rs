@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// What sysfs says about the hardware behind a device file, which stays the same when `/dev/midiN` numbering changes
+#[derive(Default, Debug)]
+pub struct DeviceInfo {
+    /// USB vendor & product id, eg. "0582:0160"
+    pub usb: Option<String>,
+    pub serial: Option<String>,
+    /// ALSA card id, eg. "JP08"
+    pub card: Option<String>
+}
+
+impl DeviceInfo {
+    /// Identify the device at a path, leaving out anything sysfs does not know (eg. for regular files)
+    pub fn identify(path: &str) -> Self {
+        let mut info = Self::default();
+        let rdev = match fs::metadata(path) {
+            Ok(md) if md.rdev() != 0 => md.rdev(),
+            _ => return info
+        };
+        let sysfs = format!("/sys/dev/char/{}:{}", libc::major(rdev), libc::minor(rdev));
+        let mut dir = match fs::canonicalize(sysfs) {
+            Ok(dir) => Some(dir),
+            Err(_) => return info
+        };
+        // the sound card & USB device are ancestors of the MIDI device in the sysfs tree
+        while let Some(current) = dir {
+            let is_card = current.file_name().map(|name| name.to_string_lossy().starts_with("card")).unwrap_or(false);
+            if info.card.is_none() && is_card {
+                info.card = read_attribute(&current, "id");
+            }
+            if let (None, Some(vendor), Some(product)) = (&info.usb, read_attribute(&current, "idVendor"), read_attribute(&current, "idProduct")) {
+                info.usb = Some(format!("{}:{}", vendor, product));
+                info.serial = read_attribute(&current, "serial");
+                break;
+            }
+            dir = current.parent().map(Path::to_path_buf);
+        }
+        info
+    }
+}
+
+fn read_attribute(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name)).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// How an alias identifies its device, every field given must match
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceMatch {
+    pub usb: Option<String>,
+    pub serial: Option<String>,
+    pub card: Option<String>
+}
+
+impl DeviceMatch {
+    fn matches(&self, info: &DeviceInfo) -> bool {
+        let field_matches = |wanted: &Option<String>, actual: &Option<String>| match wanted {
+            Some(wanted) => actual.as_ref().map(|actual| actual.eq_ignore_ascii_case(wanted)).unwrap_or(false),
+            None => true
+        };
+        field_matches(&self.usb, &info.usb) && field_matches(&self.serial, &info.serial) && field_matches(&self.card, &info.card)
+    }
+}
+
+/// Friendly names for MIDI devices, which can be used anywhere a device path is expected
+#[derive(Default, Clone)]
+pub struct DeviceAliases {
+    aliases: BTreeMap<String, DeviceMatch>
+}
+
+impl DeviceAliases {
+    /// Parse aliases from JSON, mapping each name to a DeviceMatch
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let aliases: BTreeMap<String, DeviceMatch> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        for (name, device) in &aliases {
+            if device.usb.is_none() && device.card.is_none() {
+                return Err(format!("Device alias '{}' needs a 'usb' or 'card' to identify it", name));
+            }
+        }
+        Ok(Self { aliases })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /// The path to open for a device, given either an alias or a path.
+    /// None if it is an alias for a device which is not plugged in.
+    pub fn resolve(&self, device: &str) -> Option<String> {
+        match self.aliases.get(device) {
+            Some(wanted) => midi_devices().into_iter().find(|path| wanted.matches(&DeviceInfo::identify(path))),
+            None => Some(device.to_string())
+        }
+    }
+
+    /// The alias of the device at a path, if it has one
+    pub fn name_of(&self, path: &str) -> Option<&str> {
+        let info = DeviceInfo::identify(path);
+        self.aliases.iter().find(|(_, wanted)| wanted.matches(&info)).map(|(name, _)| name.as_str())
+    }
+
+    /// Replace the paths of aliased devices with their aliases.
+    /// If several devices match an alias, only the one it resolves to is replaced.
+    pub fn label(&self, paths: Vec<String>) -> Vec<String> {
+        let mut labelled = paths;
+        for (name, wanted) in &self.aliases {
+            if let Some(path) = labelled.iter_mut().find(|path| wanted.matches(&DeviceInfo::identify(path))) {
+                *path = name.clone();
+            }
+        }
+        labelled
+    }
+}
+
+/// Every MIDI device file currently plugged in, sorted by path
+pub fn midi_devices() -> Vec<String> {
+    let mut paths: Vec<String> = match fs::read_dir("/dev") {
+        Ok(entries) => entries.filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| !path.is_dir() && path.file_name().map(|name| name.to_string_lossy().starts_with("midi")).unwrap_or(false))
+            .map(|path| path.display().to_string())
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    paths.sort();
+    paths
+}
@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use wmidi::MidiMessage;
+
+/// MIDI clock runs at 24 pulses per quarter note
+const TICKS_PER_BEAT: u32 = 24;
+/// Once no clock has been received for this long, the tempo is unknown
+const CLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often to check for a new tempo while not generating clock
+const IDLE_INTERVAL: Duration = Duration::from_millis(50);
+/// The tempos clock can be generated at, in BPM
+pub const TEMPO_RANGE: RangeInclusive<f64> = 20.0..=400.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Stopped,
+    Playing
+}
+
+pub struct ClockState {
+    transport: Transport,
+    ticks: VecDeque<Instant>,
+    generated_bpm: Option<f64>
+}
+
+impl ClockState {
+    pub fn new() -> Self {
+        Self {
+            transport: Transport::Stopped,
+            ticks: VecDeque::new(),
+            generated_bpm: None
+        }
+    }
+
+    /// Track clock & transport messages from the input, returning false if the message
+    /// should not be passed thru (because clock is being generated instead)
+    pub fn receive(&mut self, message: &MidiMessage) -> bool {
+        match message {
+            MidiMessage::TimingClock => {
+                let now = Instant::now();
+                if self.ticks.back().map(|last| now.duration_since(*last) > CLOCK_TIMEOUT).unwrap_or(false) {
+                    self.ticks.clear();
+                }
+                self.ticks.push_back(now);
+                // average over the last beat
+                while self.ticks.len() > TICKS_PER_BEAT as usize + 1 {
+                    self.ticks.pop_front();
+                }
+                return self.generated_bpm.is_none();
+            },
+            MidiMessage::Start | MidiMessage::Continue => self.transport = Transport::Playing,
+            MidiMessage::Stop => self.transport = Transport::Stopped,
+            _ => {}
+        }
+        true
+    }
+
+    /// The generated tempo if there is one, otherwise the tempo of the incoming clock
+    pub fn tempo(&self) -> Option<f64> {
+        if self.generated_bpm.is_some() {
+            return self.generated_bpm;
+        }
+        let first = self.ticks.front()?;
+        let last = self.ticks.back()?;
+        if self.ticks.len() < 2 || last.elapsed() > CLOCK_TIMEOUT {
+            return None;
+        }
+        let tick = last.duration_since(*first).as_secs_f64() / (self.ticks.len() - 1) as f64;
+        Some(60.0 / (tick * TICKS_PER_BEAT as f64))
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Start generating clock at this tempo (replacing any incoming clock), or stop if None.
+    /// Tempos outside TEMPO_RANGE are clamped to it.
+    pub fn set_generated_tempo(&mut self, bpm: Option<f64>) {
+        self.generated_bpm = bpm.filter(|bpm| *bpm > 0.0).map(|bpm| bpm.clamp(*TEMPO_RANGE.start(), *TEMPO_RANGE.end()));
+    }
+}
+
+impl Default for ClockState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Send clock to the output whenever a tempo is set, until the queue is closed or the device stops running
+pub fn generate_clock(state: Arc<Mutex<ClockState>>, tx: mpsc::Sender<MidiMessage<'static>>, running: Arc<AtomicBool>) {
+    let mut current_bpm = None;
+    let mut next_tick = Instant::now();
+    while running.load(Ordering::Relaxed) {
+        let bpm = match state.lock() {
+            Ok(state) => state.generated_bpm,
+            Err(_) => return
+        };
+        let bpm = match bpm {
+            Some(bpm) => bpm,
+            None => {
+                current_bpm = None;
+                thread::sleep(IDLE_INTERVAL);
+                continue;
+            }
+        };
+        let interval = Duration::from_secs_f64(60.0 / (bpm * TICKS_PER_BEAT as f64));
+        let now = Instant::now();
+        if current_bpm != Some(bpm) || now > next_tick + interval {
+            // tempo changed (or we fell behind), so restart the schedule from now
+            current_bpm = Some(bpm);
+            next_tick = now;
+        }
+        // in short steps, so a slow tempo does not hold up stopping
+        while next_tick > Instant::now() {
+            if !running.load(Ordering::Relaxed) {
+                return;
+            }
+            thread::sleep((next_tick - Instant::now()).min(IDLE_INTERVAL));
+        }
+        if tx.send(MidiMessage::TimingClock).is_err() {
+            return;
+        }
+        next_tick += interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_tempo_is_clamped() {
+        let mut state = ClockState::new();
+        for (bpm, generated) in [(Some(1e-300), Some(20.0)), (Some(0.01), Some(20.0)), (Some(120.0), Some(120.0)), (Some(1e9), Some(400.0)), (Some(0.0), None), (None, None)] {
+            state.set_generated_tempo(bpm);
+            assert_eq!(state.tempo(), generated, "{:?}", bpm);
+        }
+    }
+}
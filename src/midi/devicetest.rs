@@ -0,0 +1,92 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use wmidi::{MidiMessage, Channel, Note, U7};
+use super::{DeviceError, Encoder, OutputMode, Patch};
+
+/// How long the note sent by send_test is held for
+const TEST_NOTE_LENGTH: Duration = Duration::from_millis(500);
+const TEST_NOTE_VELOCITY: u8 = 100;
+
+/// Send a patch (if given) followed by a short middle C to a MIDI-OUT device, so the user can hear it is the right one
+pub fn send_test(path: &str, patch: Option<&Patch>) -> Result<(), DeviceError> {
+    let mut f = fs::File::options().write(true).open(path).map_err(|e| DeviceError::Write(format!("'{}': {}", path, e)))?;
+    let channel = patch.and_then(Patch::channel).unwrap_or(Channel::Ch1);
+    let mut messages = patch.map(Patch::messages).unwrap_or_default();
+    messages.push(MidiMessage::NoteOn(channel, Note::C4, U7::from_u8_lossy(TEST_NOTE_VELOCITY)));
+    write_messages(&mut f, &messages)?;
+    // release the note in the background, so the GUI isn't held up
+    thread::Builder::new().name("midi-test".to_string()).spawn(move || {
+        thread::sleep(TEST_NOTE_LENGTH);
+        let _ = write_messages(&mut f, &[MidiMessage::NoteOff(channel, Note::C4, U7::MIN)]);
+    }).map_err(|e| DeviceError::Write(e.to_string()))?;
+    Ok(())
+}
+
+fn write_messages(f: &mut fs::File, messages: &[MidiMessage]) -> Result<(), DeviceError> {
+    let mut encoder = Encoder::new(OutputMode::Standard);
+    let mut buf = Vec::new();
+    for message in messages {
+        encoder.encode(message, &mut buf)?;
+    }
+    f.write_all(&buf).and_then(|_| f.flush()).map_err(|e| DeviceError::Write(e.to_string()))
+}
+
+/// Watches a MIDI-IN device for anything being received, so the user can check they have chosen the right one.
+/// The device is released when this is dropped.
+pub struct InputActivity {
+    last_received: Arc<Mutex<Option<Instant>>>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl InputActivity {
+    pub fn watch(path: &str) -> Result<Self, String> {
+        let mut f = fs::File::options().read(true).open(path).map_err(|e| format!("Cannot open MIDI IN '{}': {}", path, e))?;
+        let last_received = Arc::new(Mutex::new(None));
+        let running = Arc::new(AtomicBool::new(true));
+        let last_received_clone = last_received.clone();
+        let running_clone = running.clone();
+        let thread = thread::Builder::new().name("midi-activity".to_string()).spawn(move || {
+            let mut buf: [u8; 256] = [0; 256];
+            while super::wait_for_input(&f, &running_clone) {
+                match f.read(&mut buf) {
+                    Ok(count) if count > 0 => {
+                        // active sensing & clock are sent constantly by some gear, so they don't count as activity
+                        if buf[0..count].iter().any(|b| *b < 0xF8) {
+                            if let Ok(mut last) = last_received_clone.lock() {
+                                *last = Some(Instant::now());
+                            }
+                        }
+                    },
+                    _ => return
+                }
+            }
+        }).map_err(|e| e.to_string())?;
+        Ok(Self {
+            last_received,
+            running,
+            thread: Some(thread)
+        })
+    }
+
+    /// True if anything has been received within this time
+    pub fn is_active(&self, within: Duration) -> bool {
+        match self.last_received.lock() {
+            Ok(last) => last.map(|when| when.elapsed() < within).unwrap_or(false),
+            Err(_) => false
+        }
+    }
+}
+
+impl Drop for InputActivity {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use wmidi::{MidiMessage, U7};
+use super::{DeviceError, OutputMode};
+
+/// Converts messages into the bytes written to the MIDI-OUT device
+pub struct Encoder {
+    mode: OutputMode,
+    running_status: Option<u8>,
+    message_buf: Vec<u8>
+}
+
+impl Encoder {
+    pub fn new(mode: OutputMode) -> Self {
+        Self {
+            mode,
+            running_status: None,
+            message_buf: Vec::new()
+        }
+    }
+
+    /// Forget the running status, which must be called whenever the device is reopened
+    pub fn reset(&mut self) {
+        self.running_status = None;
+    }
+
+    /// Append the bytes for this message to the buffer
+    pub fn encode(&mut self, message: &MidiMessage, buf: &mut Vec<u8>) -> Result<(), DeviceError> {
+        let message = match (self.mode, message) {
+            // note off as note on with zero velocity so it can share running status with note on
+            (OutputMode::RunningStatus, MidiMessage::NoteOff(channel, note, velocity)) if *velocity == U7::MIN => MidiMessage::NoteOn(*channel, *note, U7::MIN),
+            _ => message.clone()
+        };
+        let expected = message.bytes_size();
+        self.message_buf.resize(expected, 0);
+        match message.copy_to_slice(&mut self.message_buf) {
+            Ok(found) if found != expected => return Err(DeviceError::Encoding(format!("Not enough bytes (expected {} found {})", expected, found))),
+            Err(_) => return Err(DeviceError::Encoding(format!("Too many bytes (expected {})", expected))),
+            _ => {}
+        }
+        let status = self.message_buf[0];
+        let skip_status = match status {
+            0x80..=0xEF => {
+                let same = self.running_status == Some(status);
+                self.running_status = Some(status);
+                same && self.mode == OutputMode::RunningStatus
+            },
+            0xF0..=0xF7 => {
+                // SysEx & system common messages cancel running status
+                self.running_status = None;
+                false
+            },
+            _ => false // real-time messages don't affect running status
+        };
+        buf.extend_from_slice(if skip_status { &self.message_buf[1..] } else { &self.message_buf });
+        Ok(())
+    }
+}
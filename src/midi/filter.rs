@@ -0,0 +1,24 @@
+use super::MessageKind;
+use wmidi::MidiMessage;
+
+/// Which messages from MIDI IN are passed thru to MIDI OUT.
+/// Patch changes & playback are always sent, and triggers & clock are still handled for dropped messages.
+#[derive(Debug, Clone, Default)]
+pub struct ThruFilter {
+    /// Kinds of message never passed thru
+    pub drop: Vec<MessageKind>,
+    /// If not empty, channel messages are only passed thru on these channels (1-16)
+    pub channels: Vec<u8>
+}
+
+impl ThruFilter {
+    pub fn passes(&self, message: &MidiMessage) -> bool {
+        if self.drop.contains(&MessageKind::of(message)) {
+            return false;
+        }
+        match message.channel() {
+            Some(channel) if !self.channels.is_empty() => self.channels.contains(&channel.number()),
+            _ => true
+        }
+    }
+}
@@ -0,0 +1,1151 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashSet;
+use std::fs;
+use std::fmt;
+use std::thread;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::error::Error;
+use wmidi::{MidiMessage, ControlFunction, Channel, U7};
+
+mod parser;
+mod encoder;
+mod clock;
+mod monitor;
+mod smf;
+mod trigger;
+mod player;
+mod validate;
+mod devicetest;
+mod alias;
+mod filter;
+
+pub use parser::MidiParser;
+pub use clock::{ClockState, Transport, TEMPO_RANGE};
+pub use monitor::{Monitor, MonitorEvent, MonitorFilter, MessageKind, Direction};
+pub use smf::{SmfFormat, TimedMessage};
+pub use trigger::{Trigger, TriggerAction};
+pub use validate::validate_patches;
+pub use devicetest::{InputActivity, send_test};
+pub use alias::{DeviceAliases, DeviceInfo, midi_devices};
+pub use filter::ThruFilter;
+use encoder::Encoder;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+/// Longest time the MIDI-IN thread waits for input before checking if the device has been dropped
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Most messages written at once when batching output
+const MAX_BATCH: usize = 64;
+/// Once this many messages are waiting, the output is considered saturated
+const SATURATED_BATCH: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Every message is written in full and flushed immediately
+    Standard,
+    /// Queued messages are batched and written using running status, for slow (DIN) links
+    RunningStatus
+}
+
+#[derive(Clone)]
+pub struct DeviceOptions {
+    pub output_mode: OutputMode,
+    pub triggers: Vec<Trigger>,
+    pub record_format: SmfFormat,
+    /// Where recordings are saved
+    pub recording_folder: String,
+    /// Standard MIDI File which can be played to MIDI OUT
+    pub playback_file: Option<String>,
+    /// How channel numbers in the patch file are counted
+    pub channel_base: ChannelBase,
+    /// Names which can be given instead of device paths, looked up again whenever a device reconnects
+    pub aliases: DeviceAliases,
+    /// Name or number (from 1) of the patch to select first
+    pub start_patch: Option<String>,
+    pub thru_filter: ThruFilter,
+    pub panic: PanicOptions
+}
+
+impl Default for DeviceOptions {
+    fn default() -> Self {
+        Self {
+            output_mode: OutputMode::Standard,
+            triggers: Vec::new(),
+            record_format: SmfFormat::MultiTrack,
+            recording_folder: String::from("."),
+            playback_file: None,
+            channel_base: ChannelBase::Zero,
+            aliases: DeviceAliases::default(),
+            start_patch: None,
+            thru_filter: ThruFilter::default(),
+            panic: PanicOptions::default()
+        }
+    }
+}
+
+/// What a panic sends, and when it is sent without being asked for
+#[derive(Debug, Clone, Copy)]
+pub struct PanicOptions {
+    /// Before each new patch, in case notes are held across the change
+    pub on_patch_change: bool,
+    /// When the devices are closed
+    pub on_exit: bool,
+    /// Also send All Sound Off, which cuts off release & reverb tails as well as notes
+    pub sound_off: bool
+}
+
+impl Default for PanicOptions {
+    fn default() -> Self {
+        Self {
+            on_patch_change: false,
+            on_exit: false,
+            sound_off: true
+        }
+    }
+}
+
+/// Whether channel numbers in patch files count from 0 (0-15) or 1 (1-16, as printed on most synths)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelBase {
+    Zero,
+    One
+}
+
+impl ChannelBase {
+    pub fn parse(base: &str) -> Option<Self> {
+        match base {
+            "0" => Some(ChannelBase::Zero),
+            "1" => Some(ChannelBase::One),
+            _ => None
+        }
+    }
+
+    fn offset(&self) -> i64 {
+        match self {
+            ChannelBase::Zero => 0,
+            ChannelBase::One => 1
+        }
+    }
+
+    pub fn channel(&self, number: i64) -> Result<Channel, String> {
+        match u8::try_from(number - self.offset()).ok().and_then(|index| Channel::from_index(index).ok()) {
+            Some(channel) => Ok(channel),
+            None => Err(format!("channel {} is out of range, expected {}-{}", number, self.offset(), self.offset() + 15))
+        }
+    }
+
+    pub fn number(&self, channel: Channel) -> u8 {
+        channel.index() + self.offset() as u8
+    }
+}
+
+/// A bank select or program number, which must be 0-127 rather than being silently changed to fit
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "i64", into = "u8")]
+struct DataByte(U7);
+
+impl TryFrom<i64> for DataByte {
+    type Error = String;
+
+    fn try_from(value: i64) -> Result<Self, String> {
+        match u8::try_from(value).ok().and_then(|byte| U7::try_from(byte).ok()) {
+            Some(byte) => Ok(DataByte(byte)),
+            None => Err(format!("{} is out of range, expected 0-127", value))
+        }
+    }
+}
+
+impl From<DataByte> for u8 {
+    fn from(byte: DataByte) -> u8 {
+        u8::from(byte.0)
+    }
+}
+
+/// A patch as written in a patch file, where the channel number depends on the ChannelBase
+#[derive(Serialize, Deserialize)]
+struct PatchRecord {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bank_msb: Option<DataByte>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bank_lsb: Option<DataByte>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    program: Option<DataByte>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tempo: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>
+}
+
+pub struct Patch {
+    pub name: String,
+    channel: Option<Channel>,
+    bank_msb: Option<U7>,
+    bank_lsb: Option<U7>,
+    program: Option<U7>,
+    tempo: Option<f64>,
+    /// The name of the bank this patch belongs to, when imported from a file with bank groupings
+    group: Option<String>
+}
+
+impl Patch {
+    pub fn new(name: String, channel: Option<Channel>, bank_msb: Option<U7>, bank_lsb: Option<U7>, program: Option<U7>) -> Self {
+        Self {
+            name,
+            channel,
+            bank_msb,
+            bank_lsb,
+            program,
+            tempo: None,
+            group: None
+        }
+    }
+
+    fn from_record(record: PatchRecord, base: ChannelBase) -> Result<Self, String> {
+        let channel = match record.channel {
+            Some(number) => Some(base.channel(number).map_err(|e| format!("Patch '{}': {}", record.name, e))?),
+            None => None
+        };
+        if let Some(tempo) = record.tempo.filter(|tempo| !TEMPO_RANGE.contains(tempo)) {
+            return Err(format!("Patch '{}': tempo should be {}-{} BPM, found {:?}", record.name, TEMPO_RANGE.start(), TEMPO_RANGE.end(), tempo));
+        }
+        Ok(Self {
+            name: record.name,
+            channel,
+            bank_msb: record.bank_msb.map(|b| b.0),
+            bank_lsb: record.bank_lsb.map(|b| b.0),
+            program: record.program.map(|b| b.0),
+            tempo: record.tempo,
+            group: record.group
+        })
+    }
+
+    fn to_record(&self, base: ChannelBase) -> PatchRecord {
+        PatchRecord {
+            name: self.name.clone(),
+            channel: self.channel.map(|ch| base.number(ch) as i64),
+            bank_msb: self.bank_msb.map(DataByte),
+            bank_lsb: self.bank_lsb.map(DataByte),
+            program: self.program.map(DataByte),
+            tempo: self.tempo,
+            group: self.group.clone()
+        }
+    }
+
+    pub fn with_tempo(mut self, tempo: f64) -> Self {
+        self.tempo = Some(tempo);
+        self
+    }
+
+    pub fn with_group(mut self, group: String) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    pub fn channel(&self) -> Option<Channel> {
+        self.channel
+    }
+
+    pub fn bank_msb(&self) -> Option<u8> {
+        self.bank_msb.map(u8::from)
+    }
+
+    pub fn bank_lsb(&self) -> Option<u8> {
+        self.bank_lsb.map(u8::from)
+    }
+
+    pub fn program(&self) -> Option<u8> {
+        self.program.map(u8::from)
+    }
+
+    pub fn tempo(&self) -> Option<f64> {
+        self.tempo
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    /// True if selecting this patch would send this program (and bank, if given) on this channel
+    pub fn matches_program(&self, channel: Channel, bank_msb: Option<u8>, bank_lsb: Option<u8>, program: u8) -> bool {
+        self.channel.unwrap_or(Channel::Ch1) == channel
+            && self.program() == Some(program)
+            && (self.bank_msb.is_none() || self.bank_msb() == bank_msb)
+            && (self.bank_lsb.is_none() || self.bank_lsb() == bank_lsb)
+    }
+
+    pub fn messages(&self) -> Vec<MidiMessage<'static>> {
+        let channel = self.channel.unwrap_or(Channel::Ch1);
+        let mut messages = Vec::new();
+        if let Some(msb) = self.bank_msb {
+            messages.push(MidiMessage::ControlChange(channel, ControlFunction::BANK_SELECT, msb));
+        }
+        if let Some(lsb) = self.bank_lsb {
+            messages.push(MidiMessage::ControlChange(channel, ControlFunction::BANK_SELECT_LSB, lsb));
+        }
+        if let Some(prog) = self.program {
+            messages.push(MidiMessage::ProgramChange(channel, prog));
+        }
+        messages
+    }
+
+    pub fn send(&self, tx: &mpsc::Sender<MidiMessage<'static>>) -> Result<(), DeviceError> {
+        for message in self.messages() {
+            tx.send(message).map_err(|_| DeviceError::QueueClosed)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn load_patches(file: &str, base: ChannelBase) -> Result<Vec<Patch>, Box<dyn Error>> {
+    let json = fs::read_to_string(file).map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+    //TODO hack in some nicety for trailing commas, newlines instead of commas, non-quoted keys
+    let records: Vec<PatchRecord> = serde_json::from_str(&format!("[{}]", json)).map_err(|e| format!("Cannot parse patches from '{}': {}", file, e))?;
+    let mut patches = Vec::new();
+    for record in records {
+        patches.push(Patch::from_record(record, base).map_err(|e| format!("Cannot load patches from '{}': {}", file, e))?);
+    }
+    Ok(patches)
+}
+
+/// The index of the patch with a name, or failing that its number (counting from 1)
+fn find_patch(patches: &[Patch], name_or_number: &str) -> Option<usize> {
+    patches.iter().position(|patch| patch.name == name_or_number).or_else(|| match name_or_number.parse::<usize>() {
+        Ok(number) if (1..=patches.len()).contains(&number) => Some(number - 1),
+        _ => None
+    })
+}
+
+/// Save in the same format as the templates, one patch per line
+pub fn save_patches(file: &str, patches: &[Patch], base: ChannelBase) -> Result<(), Box<dyn Error>> {
+    let mut lines = Vec::new();
+    for patch in patches {
+        lines.push(serde_json::to_string(&patch.to_record(base))?);
+    }
+    fs::write(file, lines.join(",\n")).map_err(|e| format!("Cannot write to '{}': {}", file, e))?;
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub enum DeviceError {
+    /// A MIDI message could not be converted to bytes
+    Encoding(String),
+    /// Writing or flushing to the MIDI-OUT device failed
+    Write(String),
+    /// Reading from the MIDI-IN device failed
+    Read(String),
+    /// The queue to the MIDI-OUT thread is no longer accepting messages
+    QueueClosed,
+    /// A recording could not be saved
+    Recording(String),
+    /// A file could not be played
+    Playback(String)
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceError::Encoding(e) => write!(f, "Error encoding MIDI message: {}", e),
+            DeviceError::Write(e) => write!(f, "Error writing to MIDI OUT: {}", e),
+            DeviceError::Read(e) => write!(f, "Error reading from MIDI IN: {}", e),
+            DeviceError::QueueClosed => write!(f, "MIDI OUT is no longer accepting messages"),
+            DeviceError::Recording(e) => write!(f, "Error saving recording: {}", e),
+            DeviceError::Playback(e) => write!(f, "Error playing file: {}", e)
+        }
+    }
+}
+
+impl Error for DeviceError {}
+
+#[derive(Debug, Clone)]
+pub enum DeviceStatus {
+    InputDisconnected(DeviceError),
+    InputReconnected,
+    OutputDisconnected(DeviceError),
+    OutputReconnected,
+    Triggered(TriggerAction),
+    /// A trigger changed the current patch, to this number (from 1) & name
+    PatchChanged(usize, String),
+    RecordingStarted,
+    RecordingSaved(String),
+    PlaybackStarted(String),
+    PlaybackFinished,
+    /// A program change was played from a file, with the most recent bank select on its channel
+    PlaybackProgramChange(Channel, Option<u8>, Option<u8>, u8),
+    Failed(DeviceError)
+}
+
+impl fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceStatus::InputDisconnected(e) => write!(f, "Input device is not connected ({})", e),
+            DeviceStatus::InputReconnected => write!(f, "Input device reconnected"),
+            DeviceStatus::OutputDisconnected(e) => write!(f, "Output device is not connected ({})", e),
+            DeviceStatus::OutputReconnected => write!(f, "Output device reconnected"),
+            DeviceStatus::Triggered(action) => write!(f, "Triggered: {}", action),
+            DeviceStatus::PatchChanged(number, name) => write!(f, "Patch #{} {}", number, name),
+            DeviceStatus::RecordingStarted => write!(f, "Recording started"),
+            DeviceStatus::RecordingSaved(path) => write!(f, "Recording saved to '{}'", path),
+            DeviceStatus::PlaybackStarted(path) => write!(f, "Playing '{}'", path),
+            DeviceStatus::PlaybackFinished => write!(f, "Playback finished"),
+            DeviceStatus::PlaybackProgramChange(ch, msb, lsb, program) => write!(f, "Played program change {} (bank {:?}/{:?}) on channel {}", program, msb, lsb, ch.number()),
+            DeviceStatus::Failed(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+pub struct DeviceHealth {
+    /// None if no input device was specified
+    pub input_connected: Option<bool>,
+    pub output_connected: bool,
+    pub last_error: Option<(Instant, DeviceError)>
+}
+
+pub struct ThruDevice {
+    patch_sender: mpsc::Sender<MidiMessage<'static>>,
+    /// Where the patches were loaded from, so they can be reloaded
+    patch_file: Option<String>,
+    channel_base: ChannelBase,
+    patch_list: Vec<Patch>,
+    patch_index: usize,
+    current_messages: Arc<Mutex<Vec<MidiMessage<'static>>>>,
+    status_sender: mpsc::Sender<DeviceStatus>,
+    status_receiver: mpsc::Receiver<DeviceStatus>,
+    health: DeviceHealth,
+    clock: Arc<Mutex<ClockState>>,
+    monitor: Arc<Monitor>,
+    recording: Option<Recording>,
+    record_format: SmfFormat,
+    recording_folder: String,
+    panic: PanicOptions,
+    playback_file: Option<String>,
+    /// Set to stop the current playback
+    playback: Option<Arc<AtomicBool>>,
+    /// Cleared when the device is dropped, so its threads finish & release the MIDI devices
+    running: Arc<AtomicBool>,
+    threads: Vec<thread::JoinHandle<()>>
+}
+
+struct Recording {
+    start: Duration,
+    tempo: Option<f64>,
+    initial: Vec<MidiMessage<'static>>,
+    events: mpsc::Receiver<MonitorEvent>
+}
+
+/// Shared with the MIDI-OUT thread
+struct OutputContext {
+    status: mpsc::Sender<DeviceStatus>,
+    /// Resent when the device reconnects
+    current_messages: Arc<Mutex<Vec<MidiMessage<'static>>>>,
+    mode: OutputMode,
+    monitor: Arc<Monitor>,
+    aliases: DeviceAliases,
+    running: Arc<AtomicBool>
+}
+
+/// Shared with the MIDI-IN thread
+struct InputContext {
+    tx: mpsc::Sender<MidiMessage<'static>>,
+    status: mpsc::Sender<DeviceStatus>,
+    clock: Arc<Mutex<ClockState>>,
+    monitor: Arc<Monitor>,
+    triggers: Vec<Trigger>,
+    filter: ThruFilter,
+    aliases: DeviceAliases,
+    running: Arc<AtomicBool>
+}
+
+impl ThruDevice {
+    pub fn new(midi_in: Option<&str>, midi_out: &str, patch_file: Option<&str>, options: DeviceOptions) -> Result<Self, Box<dyn Error>> {
+        // load patches
+        let patch_list: Vec<Patch> = match patch_file {
+            Some(file) => load_patches(file, options.channel_base)?,
+            None => Vec::new()
+        };
+        let patch_index = match &options.start_patch {
+            Some(start) => find_patch(&patch_list, start).ok_or(format!("Cannot start at patch '{}', it is not in the patch file", start))?,
+            None => 0
+        };
+        // open devices & initiate midi-thru
+        let (tx, rx) = mpsc::channel();
+        let (status_tx, status_rx) = mpsc::channel();
+        let current_messages = Arc::new(Mutex::new(Vec::new()));
+        let monitor = Arc::new(Monitor::new());
+        let output_path = options.aliases.resolve(midi_out).ok_or(format!("Cannot open MIDI OUT '{}': not plugged in", midi_out))?;
+        let output = fs::File::options().write(true).open(&output_path).map_err(|e| format!("Cannot open MIDI OUT '{}': {}", midi_out, e))?;
+        // open both devices before starting any threads, so nothing is left running if one fails
+        let input = match midi_in {
+            Some(input_device) => {
+                let input_path = options.aliases.resolve(input_device).ok_or(format!("Cannot open MIDI IN '{}': not plugged in", input_device))?;
+                let input = fs::File::options().read(true).open(&input_path).map_err(|e| format!("Cannot open MIDI IN '{}': {}", input_device, e))?;
+                Some((input_path, input))
+            },
+            None => None
+        };
+        let output_device = midi_out.to_string();
+        let running = Arc::new(AtomicBool::new(true));
+        let output_context = OutputContext {
+            status: status_tx.clone(),
+            current_messages: current_messages.clone(),
+            mode: options.output_mode,
+            monitor: monitor.clone(),
+            aliases: options.aliases.clone(),
+            running: running.clone()
+        };
+        let mut threads = Vec::new();
+        threads.push(thread::Builder::new().name("midi-out".to_string()).spawn(move || write_from_queue(&output_device, output_path, output, rx, &output_context))?);
+        let clock = Arc::new(Mutex::new(ClockState::new()));
+        let clock_clone = clock.clone();
+        let tx_clone = tx.clone();
+        let running_clone = running.clone();
+        threads.push(thread::Builder::new().name("midi-clock".to_string()).spawn(move || clock::generate_clock(clock_clone, tx_clone, running_clone))?);
+        if let (Some(input_device), Some((input_path, input))) = (midi_in, input) {
+            let input_device = input_device.to_string();
+            let context = InputContext {
+                tx: tx.clone(),
+                status: status_tx.clone(),
+                clock: clock.clone(),
+                monitor: monitor.clone(),
+                triggers: options.triggers,
+                filter: options.thru_filter,
+                aliases: options.aliases,
+                running: running.clone()
+            };
+            threads.push(thread::Builder::new().name("midi-in".to_string()).spawn(move || read_into_queue(&input_device, input_path, input, &context))?);
+        }
+        // send first patch & return connected device
+        let mut device = Self {
+            patch_sender: tx,
+            patch_file: patch_file.map(String::from),
+            channel_base: options.channel_base,
+            patch_list,
+            patch_index,
+            current_messages,
+            status_sender: status_tx,
+            status_receiver: status_rx,
+            health: DeviceHealth {
+                input_connected: midi_in.map(|_| true),
+                output_connected: true,
+                last_error: None
+            },
+            clock,
+            monitor,
+            recording: None,
+            record_format: options.record_format,
+            recording_folder: options.recording_folder,
+            panic: options.panic,
+            playback_file: options.playback_file,
+            playback: None,
+            running,
+            threads
+        };
+        device.resend_patch();
+        Ok(device)
+    }
+
+    pub fn increment_patch(&mut self, delta: isize) -> Option<(usize, &Patch)> {
+        let new_index = self.patch_index as isize + delta;
+        if new_index < 0 {
+            self.set_patch(0)
+        } else {
+            self.set_patch(new_index as usize)
+        }
+    }
+
+    pub fn has_patches(&self) -> bool {
+        !self.patch_list.is_empty()
+    }
+
+    pub fn patches(&self) -> &[Patch] {
+        &self.patch_list
+    }
+
+    /// The index of a patch given by name or number (counting from 1)
+    pub fn find_patch(&self, name_or_number: &str) -> Option<usize> {
+        find_patch(&self.patch_list, name_or_number)
+    }
+
+    /// Load the patch file again (eg. after editing it), staying on the current patch if it still exists
+    pub fn reload_patches(&mut self) -> Result<usize, Box<dyn Error>> {
+        let file = self.patch_file.as_ref().ok_or("No patch file to reload")?;
+        let patch_list = load_patches(file, self.channel_base)?;
+        let current_name = self.current_patch().map(|(_, patch)| patch.name.clone());
+        self.patch_index = match current_name.and_then(|name| patch_list.iter().position(|patch| patch.name == name)) {
+            Some(index) => index,
+            None => self.patch_index.min(patch_list.len().saturating_sub(1))
+        };
+        self.patch_list = patch_list;
+        self.resend_patch();
+        Ok(self.patch_list.len())
+    }
+
+    /// Silence every note on every channel (eg. when a note is stuck)
+    pub fn panic(&self) -> Result<(), DeviceError> {
+        let functions: &[ControlFunction] = if self.panic.sound_off {
+            &[ControlFunction::DAMPER_PEDAL, ControlFunction::ALL_NOTES_OFF, ControlFunction::ALL_SOUND_OFF]
+        } else {
+            &[ControlFunction::DAMPER_PEDAL, ControlFunction::ALL_NOTES_OFF]
+        };
+        for index in 0..16 {
+            let channel = Channel::from_index(index).unwrap_or(Channel::Ch1);
+            for &function in functions {
+                self.patch_sender.send(MidiMessage::ControlChange(channel, function, U7::MIN)).map_err(|_| DeviceError::QueueClosed)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_patch(&mut self, index: usize) -> Option<(usize, &Patch)> {
+        if !self.has_patches() {
+            return None;
+        }
+        let new_index = if index >= self.patch_list.len() {
+            self.patch_list.len() - 1
+        } else {
+            index
+        };
+        if new_index != self.patch_index {
+            self.patch_index = new_index;
+            if self.panic.on_patch_change {
+                if let Err(e) = self.panic() {
+                    self.health.last_error = Some((Instant::now(), e));
+                }
+            }
+            self.resend_patch();
+            self.current_patch()
+        } else {
+            None
+        }
+    }
+
+    fn resend_patch(&mut self) {
+        if self.has_patches() {
+            let patch = &self.patch_list[self.patch_index];
+            // remember the current patch so it can be resent if the output reconnects
+            if let Ok(mut current) = self.current_messages.lock() {
+                *current = patch.messages();
+            }
+            if let Ok(mut clock) = self.clock.lock() {
+                clock.set_generated_tempo(patch.tempo);
+            }
+            if let Err(e) = patch.send(&self.patch_sender) {
+                self.health.last_error = Some((Instant::now(), e));
+            }
+        }
+    }
+
+    pub fn current_patch(&self) -> Option<(usize, &Patch)> {
+        if self.has_patches() {
+            Some((self.patch_index + 1, &self.patch_list[self.patch_index]))
+        } else {
+            None
+        }
+    }
+
+    pub fn next_patch(&self) -> Option<&Patch> {
+        self.patch_list.get(self.patch_index + 1)
+    }
+
+    pub fn previous_patch(&self) -> Option<&Patch> {
+        if self.patch_index == 0 {
+            None
+        } else {
+            self.patch_list.get(self.patch_index - 1)
+        }
+    }
+
+    /// Apply any status reported by the MIDI threads since the last check, returning what changed
+    pub fn check_status(&mut self) -> Vec<DeviceStatus> {
+        let mut changes = Vec::new();
+        while let Ok(change) = self.status_receiver.try_recv() {
+            match &change {
+                DeviceStatus::InputDisconnected(_) => self.health.input_connected = Some(false),
+                DeviceStatus::InputReconnected => self.health.input_connected = Some(true),
+                DeviceStatus::OutputDisconnected(_) => self.health.output_connected = false,
+                DeviceStatus::OutputReconnected => self.health.output_connected = true,
+                DeviceStatus::Triggered(TriggerAction::ToggleRecording) => {
+                    changes.push(change);
+                    changes.push(self.toggle_recording());
+                    continue;
+                },
+                DeviceStatus::Triggered(TriggerAction::TogglePlayback) => {
+                    changes.push(change);
+                    changes.push(self.toggle_playback());
+                    continue;
+                },
+                DeviceStatus::Triggered(action @ (TriggerAction::NextPatch | TriggerAction::PreviousPatch)) => {
+                    let delta = if *action == TriggerAction::NextPatch { 1 } else { -1 };
+                    changes.push(change);
+                    if let Some((number, patch)) = self.increment_patch(delta) {
+                        changes.push(DeviceStatus::PatchChanged(number, patch.name.clone()));
+                    }
+                    continue;
+                },
+                // only report the current playback reaching the end, not one stopped by the user (already reported) or replaced by a new playback
+                DeviceStatus::PlaybackFinished => {
+                    if !self.playback.as_ref().map(|stop| stop.load(Ordering::Relaxed)).unwrap_or(false) {
+                        continue;
+                    }
+                    self.playback = None;
+                },
+                DeviceStatus::PlaybackProgramChange(channel, bank_msb, bank_lsb, program) => {
+                    if let Some(index) = self.patch_list.iter().position(|p| p.matches_program(*channel, *bank_msb, *bank_lsb, *program)) {
+                        // follow along without resending, as the file has already sent it
+                        self.patch_index = index;
+                        if let Ok(mut current) = self.current_messages.lock() {
+                            *current = self.patch_list[index].messages();
+                        }
+                        if let Ok(mut clock) = self.clock.lock() {
+                            clock.set_generated_tempo(self.patch_list[index].tempo);
+                        }
+                    }
+                },
+                DeviceStatus::Failed(e) => self.health.last_error = Some((Instant::now(), e.clone())),
+                _ => {}
+            }
+            changes.push(change);
+        }
+        changes
+    }
+
+    /// Start recording everything sent to MIDI OUT, beginning with the current patch
+    pub fn start_recording(&mut self) {
+        let initial = match self.current_messages.lock() {
+            Ok(messages) => messages.clone(),
+            Err(_) => Vec::new()
+        };
+        self.recording = Some(Recording {
+            start: self.monitor.elapsed(),
+            tempo: self.tempo(),
+            initial,
+            events: self.monitor.subscribe()
+        });
+    }
+
+    /// Save a finished recording to a new file in the recording folder, returning the file name
+    fn save_recording(&self, recording: Recording) -> Result<String, DeviceError> {
+        let mut messages: Vec<TimedMessage> = recording.initial.into_iter()
+            .map(|message| TimedMessage { time: Duration::ZERO, message })
+            .collect();
+        for event in recording.events.try_iter() {
+            if event.direction == Direction::Out {
+                messages.push(TimedMessage {
+                    time: event.time.saturating_sub(recording.start),
+                    message: event.message
+                });
+            }
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let name = format!("recording-{}-{:03}", now.as_secs(), now.subsec_millis());
+        // never overwrite an earlier recording, even one saved in the same millisecond
+        let mut attempt = 1;
+        loop {
+            let file = if attempt == 1 { format!("{}.mid", name) } else { format!("{}-{}.mid", name, attempt) };
+            let path = Path::new(&self.recording_folder).join(file).display().to_string();
+            match smf::write(&path, &messages, self.record_format, recording.tempo) {
+                Ok(()) => return Ok(path),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(DeviceError::Recording(format!("Cannot write to '{}': {}", path, e)))
+            }
+        }
+    }
+
+    /// Start playing the playback file to MIDI OUT, mixed with any input
+    pub fn start_playback(&mut self) -> Result<String, DeviceError> {
+        let path = self.playback_file.clone().ok_or(DeviceError::Playback("No file to play".to_string()))?;
+        let messages = smf::read(&path).map_err(DeviceError::Playback)?;
+        self.stop_playback();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        let tx = self.patch_sender.clone();
+        let status = self.status_sender.clone();
+        thread::Builder::new().name("midi-player".to_string())
+            .spawn(move || player::play(messages, tx, status, stop_clone))
+            .map_err(|e| DeviceError::Playback(e.to_string()))?;
+        self.playback = Some(stop);
+        Ok(path)
+    }
+
+    pub fn stop_playback(&mut self) {
+        if let Some(stop) = self.playback.take() {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    pub fn can_play(&self) -> bool {
+        self.playback_file.is_some()
+    }
+
+    /// Start or stop playback, returning the resulting status
+    pub fn toggle_playback(&mut self) -> DeviceStatus {
+        if self.is_playing() {
+            self.stop_playback();
+            return DeviceStatus::PlaybackFinished;
+        }
+        match self.start_playback() {
+            Ok(path) => DeviceStatus::PlaybackStarted(path),
+            Err(e) => {
+                self.health.last_error = Some((Instant::now(), e.clone()));
+                DeviceStatus::Failed(e)
+            }
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Start or stop recording, returning the resulting status
+    pub fn toggle_recording(&mut self) -> DeviceStatus {
+        match self.recording.take() {
+            Some(recording) => match self.save_recording(recording) {
+                Ok(path) => DeviceStatus::RecordingSaved(path),
+                Err(e) => {
+                    self.health.last_error = Some((Instant::now(), e.clone()));
+                    DeviceStatus::Failed(e)
+                }
+            },
+            None => {
+                self.start_recording();
+                DeviceStatus::RecordingStarted
+            }
+        }
+    }
+
+    /// The tempo of the clock being generated or received, if any
+    pub fn tempo(&self) -> Option<f64> {
+        self.clock.lock().ok().and_then(|clock| clock.tempo())
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.clock.lock().map(|clock| clock.transport()).unwrap_or(Transport::Stopped)
+    }
+
+    /// Receive every message read from MIDI IN or written to MIDI OUT, until the receiver is dropped
+    pub fn monitor(&self) -> mpsc::Receiver<MonitorEvent> {
+        self.monitor.subscribe()
+    }
+
+    /// The health as of the last call to check_status()
+    pub fn health(&self) -> &DeviceHealth {
+        &self.health
+    }
+}
+
+impl Drop for ThruDevice {
+    fn drop(&mut self) {
+        self.stop_playback();
+        if self.panic.on_exit {
+            // queued before stopping, so the output thread sends it before closing the device
+            let _ = self.panic();
+        }
+        self.running.store(false, Ordering::Relaxed);
+        // wait for the devices to be closed, so they can be opened again straight away
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Returns the path opened for the device (which may have moved if it is an alias),
+/// or None if the device is dropped before it can be opened
+fn wait_for_device(device: &str, aliases: &DeviceAliases, options: &fs::OpenOptions, running: &AtomicBool) -> Option<(String, fs::File)> {
+    while running.load(Ordering::Relaxed) {
+        if let Some(path) = aliases.resolve(device) {
+            if let Ok(f) = options.open(&path) {
+                return Some((path, f));
+            }
+        }
+        thread::sleep(RECONNECT_INTERVAL);
+    }
+    None
+}
+
+fn read_into_queue(device: &str, mut path: String, mut f: fs::File, context: &InputContext) {
+    loop {
+        match read_until_disconnected(&mut f, context) {
+            Ok(()) => {
+                // queue has closed (or the device was dropped), nothing left to do
+                if context.running.load(Ordering::Relaxed) {
+                    let _ = context.status.send(DeviceStatus::Failed(DeviceError::QueueClosed));
+                }
+                return;
+            },
+            Err(e) => {
+                if context.status.send(DeviceStatus::InputDisconnected(e)).is_err() {
+                    return;
+                }
+            }
+        }
+        if fs::metadata(&path).map(|md| md.is_file()).unwrap_or(false) {
+            // a regular file has simply reached its end, reopening would replay it
+            return;
+        }
+        (path, f) = match wait_for_device(device, &context.aliases, fs::File::options().read(true), &context.running) {
+            Some(opened) => opened,
+            None => return
+        };
+        if context.status.send(DeviceStatus::InputReconnected).is_err() {
+            return;
+        }
+    }
+}
+
+/// Returns Ok if the queue closed or the device was dropped, or Err if the device could no longer be read
+fn read_until_disconnected(f: &mut fs::File, context: &InputContext) -> Result<(), DeviceError> {
+    let mut buf: [u8; 256] = [0; 256];
+    let mut parser = MidiParser::new();
+    loop {
+        if !wait_for_input(f, &context.running) {
+            return Ok(());
+        }
+        let count = f.read(&mut buf).map_err(|e| DeviceError::Read(e.to_string()))?;
+        if count == 0 {
+            return Err(DeviceError::Read("End of file".to_string()));
+        }
+        for byte in &buf[0..count] {
+            if let Some(message) = parser.parse(*byte) {
+                context.monitor.report(Direction::In, &message);
+                if !handle_input(&message, context) {
+                    continue;
+                }
+                // message complete, send to queue
+                if context.tx.send(message).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Wait until there is something to read (or an error to report), returning false if the device is dropped first
+fn wait_for_input(f: &fs::File, running: &AtomicBool) -> bool {
+    let mut poll_fd = libc::pollfd {
+        fd: f.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0
+    };
+    while running.load(Ordering::Relaxed) {
+        // SAFETY: a single pollfd is passed, which is valid for the duration of the call
+        let result = unsafe { libc::poll(&mut poll_fd, 1, INPUT_POLL_INTERVAL.as_millis() as libc::c_int) };
+        if result > 0 || (result < 0 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Handle any clock or trigger messages, returning false if the message should not be passed thru
+fn handle_input(message: &MidiMessage, context: &InputContext) -> bool {
+    let mut thru = match message {
+        MidiMessage::TimingClock | MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop => context.clock.lock().map(|mut clock| clock.receive(message)).unwrap_or(true),
+        _ => true
+    };
+    for trigger in &context.triggers {
+        if trigger.fires(message) {
+            let _ = context.status.send(DeviceStatus::Triggered(trigger.action));
+        }
+        if trigger.consumes(message) {
+            thru = false;
+        }
+    }
+    thru && context.filter.passes(message)
+}
+
+fn write_from_queue(device: &str, mut path: String, mut f: fs::File, rx: mpsc::Receiver<MidiMessage>, context: &OutputContext) {
+    let mut encoder = Encoder::new(context.mode);
+    let mut buf = Vec::new();
+    loop {
+        let error = match rx.recv_timeout(RECONNECT_INTERVAL) {
+            Ok(received) => {
+                let mut batch = vec![received];
+                if context.mode == OutputMode::RunningStatus {
+                    batch.extend(rx.try_iter().take(MAX_BATCH - 1));
+                    if batch.len() >= SATURATED_BATCH {
+                        thin_continuous_data(&mut batch);
+                    }
+                }
+                match write_batch(&mut f, &mut encoder, &mut buf, &batch, &context.status, &context.monitor) {
+                    Ok(()) => continue,
+                    Err(e) => e
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) if !context.running.load(Ordering::Relaxed) => return, // device has been dropped
+            Err(mpsc::RecvTimeoutError::Timeout) => if Path::new(&path).exists() {
+                continue;
+            } else {
+                DeviceError::Write(format!("'{}' no longer exists", path))
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => return // device has been dropped
+        };
+        // device has gone away, wait for it to come back
+        let _ = context.status.send(DeviceStatus::OutputDisconnected(error));
+        (path, f) = match wait_for_device(device, &context.aliases, fs::File::options().write(true), &context.running) {
+            Some(opened) => opened,
+            None => return
+        };
+        encoder.reset();
+        // discard anything queued while disconnected, then restore the current patch
+        while rx.try_recv().is_ok() { }
+        let current: Vec<MidiMessage> = match context.current_messages.lock() {
+            Ok(messages) => messages.clone(),
+            Err(_) => Vec::new()
+        };
+        if let Err(e) = write_batch(&mut f, &mut encoder, &mut buf, &current, &context.status, &context.monitor) {
+            let _ = context.status.send(DeviceStatus::Failed(e));
+        }
+        let _ = context.status.send(DeviceStatus::OutputReconnected);
+    }
+}
+
+/// Write all messages with a single flush, skipping (and reporting) any which cannot be encoded
+fn write_batch(f: &mut fs::File, encoder: &mut Encoder, buf: &mut Vec<u8>, batch: &[MidiMessage], status: &mpsc::Sender<DeviceStatus>, monitor: &Monitor) -> Result<(), DeviceError> {
+    buf.clear();
+    for message in batch {
+        match encoder.encode(message, buf) {
+            Ok(()) => monitor.report(Direction::Out, message),
+            Err(e) => {
+                let _ = status.send(DeviceStatus::Failed(e));
+            }
+        }
+    }
+    if let Err(e) = f.write_all(buf).and_then(|_| f.flush()) {
+        encoder.reset();
+        return Err(DeviceError::Write(e.to_string()));
+    }
+    Ok(())
+}
+
+/// Drop all but the latest value of each continuous controller, so that notes and
+/// patch changes are not held up behind controller data the output cannot keep up with.
+/// This is the only way notes & patch changes are favoured: everything left is still sent in the order received.
+fn thin_continuous_data(batch: &mut Vec<MidiMessage>) {
+    let mut seen = HashSet::new();
+    let mut keep = vec![true; batch.len()];
+    for (i, message) in batch.iter().enumerate().rev() {
+        if let Some(key) = continuous_data_key(message) {
+            if !seen.insert(key) {
+                keep[i] = false;
+            }
+        }
+    }
+    let mut keep = keep.into_iter();
+    batch.retain(|_| keep.next().unwrap_or(true));
+}
+
+/// Identifies the value a continuous message updates, or None if it must not be dropped
+fn continuous_data_key(message: &MidiMessage) -> Option<(u8, u8, u8)> {
+    match message {
+        MidiMessage::ControlChange(channel, function, _) => {
+            let controller = u8::from(*function);
+            match controller {
+                0 | 32 => None, // bank select
+                6 | 38 | 96..=101 => None, // (N)RPN selection, data entry & increment/decrement, where every value counts
+                64..=69 => None, // switches (sustain, sostenuto, etc)
+                120..=127 => None, // channel mode messages
+                _ => Some((0xB0, channel.index(), controller))
+            }
+        },
+        MidiMessage::PitchBendChange(channel, _) => Some((0xE0, channel.index(), 0)),
+        MidiMessage::ChannelPressure(channel, _) => Some((0xD0, channel.index(), 0)),
+        MidiMessage::PolyphonicKeyPressure(channel, note, _) => Some((0xA0, channel.index(), *note as u8)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(bytes: &[[u8; 3]]) -> Vec<MidiMessage<'static>> {
+        bytes.iter().map(|message| MidiMessage::try_from(message.as_slice()).unwrap().to_owned()).collect()
+    }
+
+    #[test]
+    fn thinning_keeps_parameter_changes() {
+        // pitch bend range then fine tuning, each through RPN
+        let rpn = [[0xB0, 101, 0], [0xB0, 100, 0], [0xB0, 6, 2], [0xB0, 101, 0], [0xB0, 100, 1], [0xB0, 6, 0]];
+        let mut batch = messages(&rpn);
+        thin_continuous_data(&mut batch);
+        assert_eq!(batch, messages(&rpn));
+    }
+
+    #[test]
+    fn thinning_keeps_latest_controller_value() {
+        let mut batch = messages(&[[0xB0, 1, 10], [0x90, 60, 100], [0xB0, 1, 20], [0xB1, 1, 30]]);
+        thin_continuous_data(&mut batch);
+        assert_eq!(batch, messages(&[[0x90, 60, 100], [0xB0, 1, 20], [0xB1, 1, 30]]));
+    }
+
+    /// A device sending to a file, set up to play a file which selects program 2 then holds a note for a second
+    fn playback_device(name: &str, patches: &str) -> ThruDevice {
+        let dir = std::env::temp_dir().join(format!("midi-patch-changer-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let patch_file = dir.join("patches.json");
+        fs::write(&patch_file, patches).unwrap();
+        let output = dir.join("out.mid");
+        fs::write(&output, "").unwrap();
+        // type 0 at 480 ticks per beat (& the default 120 BPM)
+        let track = [0x00, 0xC0, 0x02, 0x00, 0x90, 60, 64, 0x87, 0x40, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+        let mut smf = vec![b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0x01, 0xE0, b'M', b'T', b'r', b'k', 0, 0, 0, track.len() as u8];
+        smf.extend_from_slice(&track);
+        let playback_file = dir.join("play.mid");
+        fs::write(&playback_file, smf).unwrap();
+        let options = DeviceOptions {
+            playback_file: playback_file.to_str().map(String::from),
+            ..DeviceOptions::default()
+        };
+        ThruDevice::new(None, output.to_str().unwrap(), patch_file.to_str(), options).unwrap()
+    }
+
+    fn finished_count(changes: &[DeviceStatus]) -> usize {
+        changes.iter().filter(|change| matches!(change, DeviceStatus::PlaybackFinished)).count()
+    }
+
+    #[test]
+    fn stopping_playback_reports_finished_once() {
+        let mut device = playback_device("stop", "{\"name\":\"Piano\",\"program\":1}");
+        assert!(matches!(device.toggle_playback(), DeviceStatus::PlaybackStarted(_)));
+        thread::sleep(Duration::from_millis(200));
+        assert!(matches!(device.toggle_playback(), DeviceStatus::PlaybackFinished));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(finished_count(&device.check_status()), 0);
+        assert!(!device.is_playing());
+    }
+
+    #[test]
+    fn playback_follows_patch_tempo() {
+        let mut device = playback_device("tempo", "{\"name\":\"Piano\",\"program\":1},\n{\"name\":\"Strings\",\"program\":2,\"tempo\":90}");
+        assert_eq!(device.tempo(), None);
+        assert!(matches!(device.toggle_playback(), DeviceStatus::PlaybackStarted(_)));
+        thread::sleep(Duration::from_millis(200));
+        device.check_status();
+        assert_eq!(device.current_patch().map(|(number, _)| number), Some(2));
+        assert_eq!(device.tempo(), Some(90.0));
+        device.stop_playback();
+    }
+
+    #[test]
+    fn recordings_are_never_overwritten() {
+        let mut device = playback_device("record", "{\"name\":\"Piano\",\"program\":1}");
+        device.recording_folder = std::env::temp_dir().join(format!("midi-patch-changer-record-{}", std::process::id())).display().to_string();
+        let mut saved = HashSet::new();
+        for _ in 0..3 {
+            device.start_recording();
+            match device.toggle_recording() {
+                DeviceStatus::RecordingSaved(path) => assert!(saved.insert(path)),
+                other => panic!("{}", other)
+            }
+        }
+    }
+}
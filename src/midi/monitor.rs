@@ -0,0 +1,192 @@
+use std::fmt;
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+use wmidi::MidiMessage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Note,
+    ControlChange,
+    ProgramChange,
+    PitchBend,
+    Pressure,
+    SysEx,
+    Clock,
+    System
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 8] = [
+        MessageKind::Note,
+        MessageKind::ControlChange,
+        MessageKind::ProgramChange,
+        MessageKind::PitchBend,
+        MessageKind::Pressure,
+        MessageKind::SysEx,
+        MessageKind::Clock,
+        MessageKind::System
+    ];
+
+    pub fn of(message: &MidiMessage) -> Self {
+        match message {
+            MidiMessage::NoteOn(..) | MidiMessage::NoteOff(..) => MessageKind::Note,
+            MidiMessage::ControlChange(..) => MessageKind::ControlChange,
+            MidiMessage::ProgramChange(..) => MessageKind::ProgramChange,
+            MidiMessage::PitchBendChange(..) => MessageKind::PitchBend,
+            MidiMessage::PolyphonicKeyPressure(..) | MidiMessage::ChannelPressure(..) => MessageKind::Pressure,
+            MidiMessage::SysEx(_) | MidiMessage::OwnedSysEx(_) => MessageKind::SysEx,
+            MidiMessage::TimingClock | MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop => MessageKind::Clock,
+            _ => MessageKind::System
+        }
+    }
+
+    /// Parse the short name used on the command line
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "note" => Some(MessageKind::Note),
+            "cc" => Some(MessageKind::ControlChange),
+            "pc" => Some(MessageKind::ProgramChange),
+            "bend" => Some(MessageKind::PitchBend),
+            "pressure" => Some(MessageKind::Pressure),
+            "sysex" => Some(MessageKind::SysEx),
+            "clock" => Some(MessageKind::Clock),
+            "system" => Some(MessageKind::System),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            MessageKind::Note => "Notes",
+            MessageKind::ControlChange => "Control Change",
+            MessageKind::ProgramChange => "Program Change",
+            MessageKind::PitchBend => "Pitch Bend",
+            MessageKind::Pressure => "Pressure",
+            MessageKind::SysEx => "SysEx",
+            MessageKind::Clock => "Clock",
+            MessageKind::System => "System"
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MonitorEvent {
+    /// Time since the device was opened
+    pub time: Duration,
+    pub direction: Direction,
+    pub message: MidiMessage<'static>
+}
+
+impl MonitorEvent {
+    /// The channel number (1-16) of a channel message
+    pub fn channel(&self) -> Option<u8> {
+        self.message.channel().map(|ch| ch.number())
+    }
+}
+
+impl fmt::Display for MonitorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::In => "IN ",
+            Direction::Out => "OUT"
+        };
+        let channel = match self.channel() {
+            Some(ch) => format!("ch{:<2}", ch),
+            None => String::from("    ")
+        };
+        let description = match &self.message {
+            MidiMessage::NoteOn(_, note, velocity) => format!("Note On         {} ({}) vel {}", note, *note as u8, u8::from(*velocity)),
+            MidiMessage::NoteOff(_, note, velocity) => format!("Note Off        {} ({}) vel {}", note, *note as u8, u8::from(*velocity)),
+            MidiMessage::PolyphonicKeyPressure(_, note, value) => format!("Poly Pressure   {} ({}) {}", note, *note as u8, u8::from(*value)),
+            MidiMessage::ControlChange(_, function, value) => format!("Control Change  CC{} = {}", u8::from(*function), u8::from(*value)),
+            MidiMessage::ProgramChange(_, program) => format!("Program Change  {}", u8::from(*program)),
+            MidiMessage::ChannelPressure(_, value) => format!("Pressure        {}", u8::from(*value)),
+            MidiMessage::PitchBendChange(_, bend) => format!("Pitch Bend      {}", u16::from(*bend) as i32 - 8192),
+            MidiMessage::SysEx(data) => format!("SysEx           {} bytes", data.len()),
+            MidiMessage::OwnedSysEx(data) => format!("SysEx           {} bytes", data.len()),
+            other => format!("{:?}", other)
+        };
+        write!(f, "{:>9.3} {} {} {}", self.time.as_secs_f64(), direction, channel, description)
+    }
+}
+
+/// Which events to show, where None matches everything
+#[derive(Debug, Clone, Default)]
+pub struct MonitorFilter {
+    pub kind: Option<MessageKind>,
+    pub channel: Option<u8>
+}
+
+impl MonitorFilter {
+    pub fn matches(&self, event: &MonitorEvent) -> bool {
+        if let Some(kind) = self.kind {
+            if MessageKind::of(&event.message) != kind {
+                return false;
+            }
+        }
+        if let Some(channel) = self.channel {
+            if event.channel() != Some(channel) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Shared between the MIDI threads, which report every message to all current subscribers
+pub struct Monitor {
+    start: Instant,
+    subscribers: Mutex<Vec<mpsc::Sender<MonitorEvent>>>
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            subscribers: Mutex::new(Vec::new())
+        }
+    }
+
+    /// Start receiving events, until the receiver is dropped
+    pub fn subscribe(&self) -> mpsc::Receiver<MonitorEvent> {
+        let (tx, rx) = mpsc::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    /// Time since the monitor was created, as used for event timestamps
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn report(&self, direction: Direction, message: &MidiMessage) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            if subscribers.is_empty() {
+                return;
+            }
+            let event = MonitorEvent {
+                time: self.start.elapsed(),
+                direction,
+                message: message.clone().to_owned()
+            };
+            // drop any subscribers whose receiver has gone
+            subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+        }
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
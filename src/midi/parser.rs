@@ -0,0 +1,160 @@
+use wmidi::MidiMessage;
+
+/// Longest SysEx message which will be reassembled, anything longer is dropped
+const MAX_SYSEX_LENGTH: usize = 65536;
+
+/// Parses a stream of MIDI bytes into messages, handling running status,
+/// real-time bytes interleaved within other messages, and SysEx reassembly.
+pub struct MidiParser {
+    running_status: Option<u8>,
+    bytes: Vec<u8>,
+    in_sysex: bool
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self {
+            running_status: None,
+            bytes: Vec::new(),
+            in_sysex: false
+        }
+    }
+
+    /// Feed the next byte from the stream, returning a message if one has been completed
+    pub fn parse(&mut self, byte: u8) -> Option<MidiMessage<'static>> {
+        match byte {
+            0xF8..=0xFF => {
+                // real-time messages can appear anywhere (even mid-message) and don't affect running status
+                single_byte_message(byte)
+            },
+            0xF0 => {
+                self.running_status = None;
+                self.in_sysex = true;
+                self.bytes.clear();
+                self.bytes.push(byte);
+                None
+            },
+            0xF7 => {
+                if !self.in_sysex {
+                    return None;
+                }
+                self.in_sysex = false;
+                self.bytes.push(byte);
+                let message = MidiMessage::try_from(self.bytes.as_slice()).ok().map(|m| m.to_owned());
+                self.bytes.clear();
+                message
+            },
+            0x80..=0xEF => {
+                self.running_status = Some(byte);
+                self.start_message(byte)
+            },
+            0xF1..=0xF6 => {
+                // system common messages cancel running status
+                self.running_status = None;
+                self.start_message(byte)
+            },
+            _ => {
+                // data byte
+                if self.in_sysex {
+                    if self.bytes.len() < MAX_SYSEX_LENGTH {
+                        self.bytes.push(byte);
+                    } else {
+                        self.in_sysex = false;
+                        self.bytes.clear();
+                    }
+                    return None;
+                }
+                if self.bytes.is_empty() {
+                    match self.running_status {
+                        Some(status) => self.bytes.push(status),
+                        None => return None // stray data byte
+                    }
+                }
+                self.bytes.push(byte);
+                self.complete_message()
+            }
+        }
+    }
+
+    fn start_message(&mut self, status: u8) -> Option<MidiMessage<'static>> {
+        // a new status byte aborts any incomplete message (including unterminated SysEx)
+        self.in_sysex = false;
+        self.bytes.clear();
+        self.bytes.push(status);
+        self.complete_message()
+    }
+
+    fn complete_message(&mut self) -> Option<MidiMessage<'static>> {
+        if self.bytes.len() < expected_length(self.bytes[0]) {
+            return None;
+        }
+        let message = MidiMessage::try_from(self.bytes.as_slice()).ok().map(|m| m.to_owned());
+        self.bytes.clear();
+        message
+    }
+}
+
+impl Default for MidiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn single_byte_message(byte: u8) -> Option<MidiMessage<'static>> {
+    MidiMessage::try_from([byte].as_slice()).ok().map(|m| m.to_owned())
+}
+
+/// The number of bytes (including status) of a non-SysEx message
+fn expected_length(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 3,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 2,
+        _ => 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed a captured byte stream through a parser, returning the bytes of each message that comes out
+    fn parse_all(stream: &[u8]) -> Vec<Vec<u8>> {
+        let mut parser = MidiParser::new();
+        stream.iter().filter_map(|byte| parser.parse(*byte)).map(|message| message.to_vec()).collect()
+    }
+
+    #[test]
+    fn running_status() {
+        assert_eq!(parse_all(&[0x90, 0x3C, 0x40, 0x3E, 0x40]), vec![vec![0x90, 0x3C, 0x40], vec![0x90, 0x3E, 0x40]]);
+    }
+
+    #[test]
+    fn clock_inside_message() {
+        assert_eq!(parse_all(&[0xB0, 0x07, 0xF8, 0x64]), vec![vec![0xF8], vec![0xB0, 0x07, 0x64]]);
+    }
+
+    #[test]
+    fn sysex_with_real_time_byte() {
+        assert_eq!(parse_all(&[0xF0, 0x41, 0x10, 0xFE, 0x42, 0x12, 0xF7]), vec![vec![0xFE], vec![0xF0, 0x41, 0x10, 0x42, 0x12, 0xF7]]);
+    }
+
+    #[test]
+    fn sysex_too_long_is_dropped() {
+        let mut stream = vec![0xF0];
+        stream.extend(std::iter::repeat_n(0x01, MAX_SYSEX_LENGTH + 10));
+        stream.extend([0xF7, 0xC0, 0x05]);
+        assert_eq!(parse_all(&stream), vec![vec![0xC0, 0x05]]);
+    }
+
+    #[test]
+    fn status_interrupts_partial_message() {
+        assert_eq!(parse_all(&[0x90, 0x3C, 0x80, 0x3C, 0x00]), vec![vec![0x80, 0x3C, 0x00]]);
+    }
+
+    #[test]
+    fn stray_data_after_system_common() {
+        // running status was cancelled by the song select, so the extra data byte is ignored
+        assert_eq!(parse_all(&[0x90, 0x3C, 0x40, 0xF3, 0x02, 0x3E, 0x40, 0x90, 0x3E, 0x40]),
+            vec![vec![0x90, 0x3C, 0x40], vec![0xF3, 0x02], vec![0x90, 0x3E, 0x40]]);
+    }
+}
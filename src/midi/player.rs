@@ -0,0 +1,59 @@
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use wmidi::{MidiMessage, ControlFunction, Channel, U7};
+use super::{DeviceStatus, TimedMessage};
+
+/// Longest time to sleep before checking if playback has been stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Send the messages to the output queue at their times, reporting program changes so the current patch can follow along
+pub fn play(messages: Vec<TimedMessage>, tx: mpsc::Sender<MidiMessage<'static>>, status: mpsc::Sender<DeviceStatus>, stop: Arc<AtomicBool>) {
+    let start = Instant::now();
+    let mut banks = [(None, None); 16];
+    let mut used_channels = [false; 16];
+    for timed in messages {
+        // wait until it's time for this message
+        loop {
+            // stopped by the user, who has already been told playback finished
+            if stop.load(Ordering::Relaxed) {
+                all_notes_off(&tx, &used_channels);
+                return;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timed.time {
+                break;
+            }
+            thread::sleep((timed.time - elapsed).min(STOP_CHECK_INTERVAL));
+        }
+        match &timed.message {
+            MidiMessage::ControlChange(ch, ControlFunction::BANK_SELECT, value) => banks[ch.index() as usize].0 = Some(u8::from(*value)),
+            MidiMessage::ControlChange(ch, ControlFunction::BANK_SELECT_LSB, value) => banks[ch.index() as usize].1 = Some(u8::from(*value)),
+            MidiMessage::ProgramChange(ch, program) => {
+                let (bank_msb, bank_lsb) = banks[ch.index() as usize];
+                let _ = status.send(DeviceStatus::PlaybackProgramChange(*ch, bank_msb, bank_lsb, u8::from(*program)));
+            },
+            _ => {}
+        }
+        if let Some(ch) = timed.message.channel() {
+            used_channels[ch.index() as usize] = true;
+        }
+        if tx.send(timed.message).is_err() {
+            return;
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    let _ = status.send(DeviceStatus::PlaybackFinished);
+}
+
+/// Make sure no notes are left hanging when playback is stopped part way through
+fn all_notes_off(tx: &mpsc::Sender<MidiMessage<'static>>, used_channels: &[bool; 16]) {
+    for (index, used) in used_channels.iter().enumerate() {
+        if *used {
+            let channel = Channel::from_index(index as u8).unwrap();
+            let _ = tx.send(MidiMessage::ControlChange(channel, ControlFunction::ALL_NOTES_OFF, U7::MIN));
+        }
+    }
+}
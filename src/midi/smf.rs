@@ -0,0 +1,287 @@
+use std::fs;
+use std::io::{self, Write};
+use std::time::Duration;
+use wmidi::MidiMessage;
+use super::MidiParser;
+
+/// Ticks per quarter note used when writing files
+const DIVISION: u16 = 960;
+const DEFAULT_BPM: f64 = 120.0;
+const DEFAULT_MICROSECONDS_PER_BEAT: u32 = 500_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmfFormat {
+    /// Type 0, all events in one track
+    SingleTrack,
+    /// Type 1, a tempo track followed by one track per MIDI channel
+    MultiTrack
+}
+
+impl SmfFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "0" => Some(SmfFormat::SingleTrack),
+            "1" => Some(SmfFormat::MultiTrack),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimedMessage {
+    pub time: Duration,
+    pub message: MidiMessage<'static>
+}
+
+/// Write the messages (which must be in time order) to a new Standard MIDI File, ignoring
+/// real-time and system common messages which cannot be stored. Fails if the file already exists.
+pub fn write(path: &str, messages: &[TimedMessage], format: SmfFormat, bpm: Option<f64>) -> io::Result<()> {
+    let bpm = bpm.unwrap_or(DEFAULT_BPM);
+    let ticks_per_second = bpm / 60.0 * DIVISION as f64;
+    let microseconds_per_beat = (60_000_000.0 / bpm).round() as u32;
+    let mut tempo_track = TrackWriter::new();
+    tempo_track.meta(0, 0x51, &microseconds_per_beat.to_be_bytes()[1..]);
+    let mut tracks = match format {
+        SmfFormat::SingleTrack => Vec::new(),
+        SmfFormat::MultiTrack => vec![None; 16]
+    };
+    for timed in messages {
+        let tick = (timed.time.as_secs_f64() * ticks_per_second).round() as u32;
+        let track = match (format, timed.message.channel()) {
+            (SmfFormat::MultiTrack, Some(ch)) => tracks[ch.index() as usize].get_or_insert_with(TrackWriter::new),
+            _ => &mut tempo_track
+        };
+        track.message(tick, &timed.message);
+    }
+    let tracks: Vec<TrackWriter> = tracks.into_iter().flatten().collect();
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"MThd");
+    bytes.extend_from_slice(&6u32.to_be_bytes());
+    bytes.extend_from_slice(&match format { SmfFormat::SingleTrack => 0u16, SmfFormat::MultiTrack => 1u16 }.to_be_bytes());
+    bytes.extend_from_slice(&(1 + tracks.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&DIVISION.to_be_bytes());
+    tempo_track.finish(&mut bytes);
+    for track in tracks {
+        track.finish(&mut bytes);
+    }
+    fs::OpenOptions::new().write(true).create_new(true).open(path)?.write_all(&bytes)
+}
+
+/// Read all channel & SysEx messages from a Standard MIDI File (of any type), merged into time order
+pub fn read(path: &str) -> Result<Vec<TimedMessage>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Cannot read from '{}': {}", path, e))?;
+    let mut reader = ByteReader { bytes: &bytes, position: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err(format!("'{}' is not a Standard MIDI File", path));
+    }
+    let header_length = reader.u32()? as usize;
+    let header = reader.take(header_length)?;
+    if header.len() < 6 {
+        return Err(format!("'{}' has an invalid header", path));
+    }
+    let track_count = u16::from_be_bytes([header[2], header[3]]);
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    // (tick, track, order within track, event)
+    let mut events: Vec<(u32, usize, usize, TrackEvent)> = Vec::new();
+    for track in 0..track_count as usize {
+        let chunk_type = reader.take(4)?;
+        let length = reader.u32()? as usize;
+        let data = reader.take(length)?;
+        if chunk_type != b"MTrk" {
+            continue; // unknown chunks must be ignored
+        }
+        for (order, (tick, event)) in read_track(data)?.into_iter().enumerate() {
+            events.push((tick, track, order, event));
+        }
+    }
+    events.sort_by_key(|(tick, track, order, _)| (*tick, *track, *order));
+    // convert ticks to time, following any tempo changes
+    let mut messages = Vec::new();
+    let mut microseconds_per_beat = DEFAULT_MICROSECONDS_PER_BEAT;
+    let mut last_tick = 0;
+    let mut time = Duration::ZERO;
+    for (tick, _, _, event) in events {
+        time += tick_duration(tick - last_tick, division, microseconds_per_beat);
+        last_tick = tick;
+        match event {
+            TrackEvent::Tempo(tempo) => microseconds_per_beat = tempo,
+            TrackEvent::Message(message) => messages.push(TimedMessage { time, message })
+        }
+    }
+    Ok(messages)
+}
+
+enum TrackEvent {
+    Tempo(u32),
+    Message(MidiMessage<'static>)
+}
+
+fn read_track(data: &[u8]) -> Result<Vec<(u32, TrackEvent)>, String> {
+    let mut reader = ByteReader { bytes: data, position: 0 };
+    let mut events = Vec::new();
+    let mut tick = 0;
+    let mut running_status = None;
+    while !reader.is_empty() {
+        tick += reader.variable_length()?;
+        let mut status = reader.u8()?;
+        if status < 0x80 {
+            // running status, this byte was actually data
+            status = running_status.ok_or("Track data is missing a status byte")?;
+            reader.position -= 1;
+        }
+        match status {
+            0xFF => {
+                let meta_type = reader.u8()?;
+                let length = reader.variable_length()? as usize;
+                let meta = reader.take(length)?;
+                match meta_type {
+                    0x2F => break, // end of track
+                    0x51 if meta.len() == 3 => events.push((tick, TrackEvent::Tempo(u32::from_be_bytes([0, meta[0], meta[1], meta[2]])))),
+                    _ => {}
+                }
+            },
+            0xF0 | 0xF7 => {
+                let length = reader.variable_length()? as usize;
+                let data = reader.take(length)?;
+                if status == 0xF0 {
+                    let mut parser = MidiParser::new();
+                    if let Some(message) = std::iter::once(&0xF0).chain(data).filter_map(|b| parser.parse(*b)).last() {
+                        events.push((tick, TrackEvent::Message(message)));
+                    }
+                }
+            },
+            0x80..=0xEF => {
+                running_status = Some(status);
+                let length = if (0xC0..=0xDF).contains(&status) { 1 } else { 2 };
+                let mut message_bytes = vec![status];
+                message_bytes.extend_from_slice(reader.take(length)?);
+                if let Ok(message) = MidiMessage::try_from(message_bytes.as_slice()) {
+                    events.push((tick, TrackEvent::Message(message.to_owned())));
+                }
+            },
+            _ => return Err(format!("Unexpected status byte 0x{:02X} in track", status))
+        }
+    }
+    Ok(events)
+}
+
+fn tick_duration(ticks: u32, division: u16, microseconds_per_beat: u32) -> Duration {
+    if division & 0x8000 == 0 {
+        // ticks per quarter note
+        Duration::from_micros(ticks as u64 * microseconds_per_beat as u64 / division.max(1) as u64)
+    } else {
+        // SMPTE frames per second & ticks per frame
+        let frames_per_second = 256 - (division >> 8);
+        let ticks_per_frame = (division & 0xFF).max(1);
+        Duration::from_secs_f64(ticks as f64 / (frames_per_second as f64 * ticks_per_frame as f64))
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> ByteReader<'a> {
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.position + count > self.bytes.len() {
+            return Err("Unexpected end of file".to_string());
+        }
+        let slice = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn variable_length(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = (value << 7) | (b & 0x7F) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Invalid variable length quantity".to_string())
+    }
+}
+
+#[derive(Clone)]
+struct TrackWriter {
+    bytes: Vec<u8>,
+    last_tick: u32
+}
+
+impl TrackWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            last_tick: 0
+        }
+    }
+
+    fn delta(&mut self, tick: u32) {
+        let tick = tick.max(self.last_tick);
+        write_variable_length(&mut self.bytes, tick - self.last_tick);
+        self.last_tick = tick;
+    }
+
+    fn meta(&mut self, tick: u32, meta_type: u8, data: &[u8]) {
+        self.delta(tick);
+        self.bytes.extend_from_slice(&[0xFF, meta_type]);
+        write_variable_length(&mut self.bytes, data.len() as u32);
+        self.bytes.extend_from_slice(data);
+    }
+
+    fn message(&mut self, tick: u32, message: &MidiMessage) {
+        let mut buf = vec![0; message.bytes_size()];
+        if message.copy_to_slice(&mut buf).is_err() {
+            return;
+        }
+        match buf[0] {
+            0x80..=0xEF => {
+                self.delta(tick);
+                self.bytes.extend_from_slice(&buf);
+            },
+            0xF0 => {
+                // SysEx is stored with its length instead of the leading 0xF0
+                self.delta(tick);
+                self.bytes.push(0xF0);
+                write_variable_length(&mut self.bytes, buf.len() as u32 - 1);
+                self.bytes.extend_from_slice(&buf[1..]);
+            },
+            _ => {}
+        }
+    }
+
+    fn finish(mut self, out: &mut Vec<u8>) {
+        let end = self.last_tick;
+        self.meta(end, 0x2F, &[]);
+        out.extend_from_slice(b"MTrk");
+        out.extend_from_slice(&(self.bytes.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.bytes);
+    }
+}
+
+fn write_variable_length(bytes: &mut Vec<u8>, mut value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    groups.reverse();
+    bytes.extend_from_slice(&groups);
+}
@@ -0,0 +1,93 @@
+use std::fmt;
+use wmidi::MidiMessage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerAction {
+    ToggleRecording,
+    TogglePlayback,
+    NextPatch,
+    PreviousPatch
+}
+
+impl fmt::Display for TriggerAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TriggerAction::ToggleRecording => write!(f, "Toggle recording"),
+            TriggerAction::TogglePlayback => write!(f, "Toggle playback"),
+            TriggerAction::NextPatch => write!(f, "Next patch"),
+            TriggerAction::PreviousPatch => write!(f, "Previous patch")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TriggerKind {
+    Note,
+    ControlChange,
+    ProgramChange
+}
+
+/// A message from MIDI IN which performs an action instead of being passed thru
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    kind: TriggerKind,
+    number: u8,
+    /// 1-16, or None for any channel
+    channel: Option<u8>,
+    pub action: TriggerAction
+}
+
+impl Trigger {
+    /// Parse a trigger in the form TYPE:NUMBER[:CHANNEL], where TYPE is note, cc or pc (eg. "cc:80" or "note:60:10")
+    pub fn parse(spec: &str, action: TriggerAction) -> Result<Self, String> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(format!("Invalid trigger '{}', expected TYPE:NUMBER[:CHANNEL]", spec));
+        }
+        let kind = match parts[0].to_lowercase().as_str() {
+            "note" => TriggerKind::Note,
+            "cc" => TriggerKind::ControlChange,
+            "pc" => TriggerKind::ProgramChange,
+            other => return Err(format!("Invalid trigger type '{}', expected note, cc or pc", other))
+        };
+        let number = match parts[1].parse::<u8>() {
+            Ok(n) if n < 128 => n,
+            _ => return Err(format!("Invalid trigger number '{}', expected 0-127", parts[1]))
+        };
+        let channel = match parts.get(2) {
+            Some(ch) => match ch.parse::<u8>() {
+                Ok(n) if (1..=16).contains(&n) => Some(n),
+                _ => return Err(format!("Invalid trigger channel '{}', expected 1-16", ch))
+            },
+            None => None
+        };
+        Ok(Self {
+            kind,
+            number,
+            channel,
+            action
+        })
+    }
+
+    /// True if this message should fire the trigger
+    pub fn fires(&self, message: &MidiMessage) -> bool {
+        let (kind, channel, number) = match message {
+            MidiMessage::NoteOn(ch, note, _) => (TriggerKind::Note, ch, *note as u8),
+            MidiMessage::ControlChange(ch, function, value) if u8::from(*value) >= 64 => (TriggerKind::ControlChange, ch, u8::from(*function)),
+            MidiMessage::ProgramChange(ch, program) => (TriggerKind::ProgramChange, ch, u8::from(*program)),
+            _ => return false
+        };
+        kind == self.kind && number == self.number && self.channel.map(|c| c == channel.number()).unwrap_or(true)
+    }
+
+    /// True if this message belongs to the trigger (including its release), so should not be passed thru
+    pub fn consumes(&self, message: &MidiMessage) -> bool {
+        let (kind, channel, number) = match message {
+            MidiMessage::NoteOn(ch, note, _) | MidiMessage::NoteOff(ch, note, _) => (TriggerKind::Note, ch, *note as u8),
+            MidiMessage::ControlChange(ch, function, _) => (TriggerKind::ControlChange, ch, u8::from(*function)),
+            MidiMessage::ProgramChange(ch, program) => (TriggerKind::ProgramChange, ch, u8::from(*program)),
+            _ => return false
+        };
+        kind == self.kind && number == self.number && self.channel.map(|c| c == channel.number()).unwrap_or(true)
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use serde_json::Value;
+use super::{ChannelBase, TEMPO_RANGE};
+
+const FIELDS: [&str; 7] = ["name", "channel", "bank_msb", "bank_lsb", "program", "tempo", "group"];
+
+/// Check a patch file for mistakes which loading it would either reject or silently change,
+/// returning a description of each problem found (or an error if it cannot be parsed at all)
+pub fn validate_patches(file: &str, base: ChannelBase) -> Result<Vec<String>, Box<dyn Error>> {
+    let json = fs::read_to_string(file).map_err(|e| format!("Cannot read from '{}': {}", file, e))?;
+    let values: Vec<Value> = serde_json::from_str(&format!("[{}]", json)).map_err(|e| format!("Cannot parse patches from '{}': {}", file, e))?;
+    let mut problems = Vec::new();
+    let mut names: HashMap<String, usize> = HashMap::new();
+    for (index, value) in values.iter().enumerate() {
+        let number = index + 1;
+        let patch = match value.as_object() {
+            Some(patch) => patch,
+            None => {
+                problems.push(format!("Patch {}: expected an object, found '{}'", number, value));
+                continue;
+            }
+        };
+        for key in patch.keys() {
+            if !FIELDS.contains(&key.as_str()) {
+                problems.push(format!("Patch {}: unknown field '{}'", number, key));
+            }
+        }
+        match patch.get("name") {
+            Some(Value::String(name)) if name.trim().is_empty() => problems.push(format!("Patch {}: empty name", number)),
+            Some(Value::String(name)) => match names.get(name) {
+                Some(first) => problems.push(format!("Patch {}: duplicate name '{}' (also patch {})", number, name, first)),
+                None => {
+                    names.insert(name.clone(), number);
+                }
+            },
+            Some(other) => problems.push(format!("Patch {}: name should be a string, found '{}'", number, other)),
+            None => problems.push(format!("Patch {}: missing name", number))
+        }
+        match patch.get("channel") {
+            None | Some(Value::Null) => {},
+            Some(channel) => match channel.as_i64() {
+                Some(n) => if let Err(e) = base.channel(n) {
+                    problems.push(format!("Patch {}: {}", number, e));
+                },
+                None => problems.push(format!("Patch {}: channel should be a number, found '{}'", number, channel))
+            }
+        }
+        for field in ["bank_msb", "bank_lsb", "program"] {
+            match patch.get(field) {
+                None | Some(Value::Null) => {},
+                Some(value) => match value.as_u64() {
+                    Some(n) if n <= 127 => {},
+                    _ => problems.push(format!("Patch {}: {} should be a number 0-127, found '{}'", number, field, value))
+                }
+            }
+        }
+        match patch.get("tempo") {
+            None | Some(Value::Null) => {},
+            Some(tempo) => match tempo.as_f64() {
+                Some(bpm) if TEMPO_RANGE.contains(&bpm) => {},
+                _ => problems.push(format!("Patch {}: tempo should be {}-{} BPM, found '{}'", number, TEMPO_RANGE.start(), TEMPO_RANGE.end(), tempo))
+            }
+        }
+        match patch.get("group") {
+            None | Some(Value::Null) | Some(Value::String(_)) => {},
+            Some(other) => problems.push(format!("Patch {}: group should be a string, found '{}'", number, other))
+        }
+    }
+    Ok(problems)
+}
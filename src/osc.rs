@@ -0,0 +1,428 @@
+use crate::control::{self, Remote};
+use crate::midi;
+use std::cell::RefCell;
+use std::iter;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for a packet before checking whether to stop
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Largest packet accepted, far more than any patch command needs
+const MAX_PACKET: usize = 4096;
+
+/// An argument of an OSC message, of the types sent by control surfaces & lighting desks
+#[derive(Debug, PartialEq)]
+enum Argument {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool)
+}
+
+impl Argument {
+    fn tag(&self) -> char {
+        match self {
+            Self::Int(_) => 'i',
+            Self::Float(_) => 'f',
+            Self::String(_) => 's',
+            Self::Bool(true) => 'T',
+            Self::Bool(false) => 'F'
+        }
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Self::Int(i) => Some(*i as f64),
+            Self::Float(f) => Some(*f as f64),
+            Self::String(s) => s.trim().parse().ok(),
+            Self::Bool(b) => Some(if *b { 1.0 } else { 0.0 })
+        }
+    }
+}
+
+/// An OSC message received from a client
+struct Message {
+    address: String,
+    arguments: Vec<Argument>,
+    from: SocketAddr
+}
+
+/// Listens for OSC messages over UDP:
+/// - /patch/next, /patch/prev, /patch/set NUMBER|NAME, /patch/panic & /patch/reload control the patch system
+/// - /patch/current replies with the current patch
+/// - /patch/subscribe [PORT] sends /patch/current NUMBER NAME to the client (on PORT if given) whenever the patch changes,
+///   until /patch/unsubscribe [PORT]
+///
+/// Failed commands are answered with /patch/error MESSAGE.
+pub struct OscServer {
+    socket: UdpSocket,
+    messages: mpsc::Receiver<Message>,
+    subscribers: RefCell<Vec<SocketAddr>>,
+    /// The patch subscribers were last told about
+    last_patch: RefCell<Option<(usize, String)>>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl OscServer {
+    pub fn start(address: SocketAddr) -> Result<Self, String> {
+        let socket = UdpSocket::bind(address).map_err(|e| format!("Cannot listen for OSC on {}: {}", address, e))?;
+        let receiver = socket.try_clone().map_err(|e| format!("Cannot listen for OSC on {}: {}", address, e))?;
+        receiver.set_read_timeout(Some(RECEIVE_TIMEOUT)).map_err(|e| format!("Cannot listen for OSC on {}: {}", address, e))?;
+        let (tx, rx) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+        let thread = thread::Builder::new().name("midi-osc".to_string()).spawn(move || receive_messages(receiver, tx, &running_clone))
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            socket,
+            messages: rx,
+            subscribers: RefCell::new(Vec::new()),
+            last_patch: RefCell::new(None),
+            running,
+            thread: Some(thread)
+        })
+    }
+
+    fn send(&self, to: SocketAddr, address: &str, arguments: &[Argument]) {
+        // UDP is fire & forget, so a client which has gone away is not an error
+        let _ = self.socket.send_to(&encode(address, arguments), to);
+    }
+
+    fn send_patch(&self, to: SocketAddr, patch: &Option<(usize, String)>) {
+        let (number, name) = match patch {
+            Some((number, name)) => (*number as i32, name.clone()),
+            None => (0, String::new())
+        };
+        self.send(to, "/patch/current", &[Argument::Int(number), Argument::String(name)]);
+    }
+
+    /// Where a client wants patch changes sent: the port given, or where it sent from
+    fn subscriber(message: &Message) -> Result<SocketAddr, String> {
+        match message.arguments.first() {
+            Some(argument) => match argument.as_number() {
+                Some(port) if (1.0..=65535.0).contains(&port) => Ok(SocketAddr::new(message.from.ip(), port as u16)),
+                _ => Err(String::from("Invalid port, expected 1-65535"))
+            },
+            None => Ok(message.from)
+        }
+    }
+}
+
+impl Remote for OscServer {
+    fn handle_requests(&self, mut device: Option<&mut midi::ThruDevice>) -> Vec<String> {
+        let mut handled = Vec::new();
+        while let Ok(message) = self.messages.try_recv() {
+            let result = match message.address.as_str() {
+                "/patch/subscribe" => Self::subscriber(&message).map(|subscriber| {
+                    let mut subscribers = self.subscribers.borrow_mut();
+                    if !subscribers.contains(&subscriber) {
+                        subscribers.push(subscriber);
+                    }
+                    // unless subscribers are about to hear of a change anyway
+                    if let Some(patch) = device.as_deref().map(current_patch).filter(|patch| *self.last_patch.borrow() == *patch) {
+                        self.send_patch(subscriber, &patch);
+                    }
+                    format!("OK Subscribed {}", subscriber)
+                }),
+                "/patch/unsubscribe" => Self::subscriber(&message).map(|subscriber| {
+                    self.subscribers.borrow_mut().retain(|s| *s != subscriber);
+                    format!("OK Unsubscribed {}", subscriber)
+                }),
+                address => match command_for(address, &message.arguments) {
+                    Ok(Some(command)) => {
+                        let result = control::execute(&command, device.as_deref_mut()).pop().unwrap_or_default();
+                        match (result.strip_prefix("ERR "), device.as_deref()) {
+                            (Some(error), _) => Err(error.to_string()),
+                            (None, Some(device)) if command == "current" => {
+                                self.send_patch(message.from, &current_patch(device));
+                                Ok(result)
+                            },
+                            (None, _) => Ok(result)
+                        }
+                    },
+                    Ok(None) => continue,
+                    Err(e) => Err(e)
+                }
+            };
+            let result = match result {
+                Ok(result) => result,
+                Err(error) => {
+                    self.send(message.from, "/patch/error", &[Argument::String(error.clone())]);
+                    format!("ERR {}", error)
+                }
+            };
+            handled.push(format!("OSC: {} from {} ({})", message.address, message.from, result));
+        }
+        // tell subscribers about every change, including those made by the GUI, MIDI triggers & other remotes
+        if let Some(device) = device {
+            let patch = current_patch(device);
+            if *self.last_patch.borrow() != patch {
+                for subscriber in self.subscribers.borrow().iter() {
+                    self.send_patch(*subscriber, &patch);
+                }
+                self.last_patch.replace(patch);
+            }
+        }
+        handled
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn current_patch(device: &midi::ThruDevice) -> Option<(usize, String)> {
+    device.current_patch().map(|(number, patch)| (number, patch.name.clone()))
+}
+
+/// The control command for an OSC address, or None if the message should be ignored
+fn command_for(address: &str, arguments: &[Argument]) -> Result<Option<String>, String> {
+    let command = match address {
+        "/patch/next" => "next",
+        "/patch/prev" | "/patch/previous" => "previous",
+        "/patch/panic" => "panic",
+        "/patch/reload" => "reload",
+        "/patch/current" => "current",
+        "/patch/set" => return match arguments.first() {
+            Some(Argument::String(name)) => Ok(Some(format!("set {}", name))),
+            Some(argument) => match argument.as_number() {
+                Some(number) => Ok(Some(format!("set {}", number.round() as i64))),
+                None => Err(String::from("/patch/set needs a patch number or name"))
+            },
+            None => Err(String::from("/patch/set needs a patch number or name"))
+        },
+        // a lighting desk may send all sorts of things to everyone listening
+        other if !other.starts_with("/patch/") => return Ok(None),
+        other => return Err(format!("Unknown address '{}'", other))
+    };
+    // buttons on control surfaces send 1 when pressed & 0 when released
+    match arguments.first().and_then(Argument::as_number) {
+        Some(0.0) => Ok(None),
+        _ => Ok(Some(command.to_string()))
+    }
+}
+
+fn receive_messages(socket: UdpSocket, tx: mpsc::Sender<Message>, running: &AtomicBool) {
+    let mut buffer = [0; MAX_PACKET];
+    while running.load(Ordering::Relaxed) {
+        // times out regularly, to check whether to stop
+        let (length, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(_) => continue
+        };
+        // packets which are not valid OSC are ignored
+        let mut messages = Vec::new();
+        if decode(&buffer[..length], &mut messages).is_none() {
+            continue;
+        }
+        for (address, arguments) in messages {
+            if tx.send(Message { address, arguments, from }).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Decode an OSC packet, unpacking bundles (which are carried out straight away, whatever their time tag)
+fn decode(packet: &[u8], messages: &mut Vec<(String, Vec<Argument>)>) -> Option<()> {
+    let mut reader = Reader { data: packet };
+    if packet.starts_with(b"#bundle\0") {
+        reader.take(16)?; // "#bundle" & the time tag
+        while !reader.data.is_empty() {
+            let size = usize::try_from(reader.int()?).ok()?;
+            decode(reader.take(size)?, messages)?;
+        }
+    } else {
+        let address = reader.string()?;
+        // very old clients leave out the type tags when there are no arguments
+        let tags = if reader.data.is_empty() { String::from(",") } else { reader.string()? };
+        let mut arguments = Vec::new();
+        for tag in tags.strip_prefix(',')?.chars() {
+            arguments.push(match tag {
+                'i' => Argument::Int(reader.int()?),
+                'f' => Argument::Float(f32::from_bits(reader.int()? as u32)),
+                's' => Argument::String(reader.string()?),
+                'T' => Argument::Bool(true),
+                'F' => Argument::Bool(false),
+                _ => return None
+            });
+        }
+        messages.push((address, arguments));
+    }
+    Some(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if length > self.data.len() {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn int(&mut self) -> Option<i32> {
+        Some(i32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    /// Strings end with a null, padded to a multiple of 4 bytes
+    fn string(&mut self) -> Option<String> {
+        let end = self.data.iter().position(|b| *b == 0)?;
+        let string = String::from_utf8(self.data[..end].to_vec()).ok()?;
+        self.take((end / 4 + 1) * 4)?;
+        Some(string)
+    }
+}
+
+fn encode(address: &str, arguments: &[Argument]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    write_string(&mut packet, address);
+    write_string(&mut packet, &iter::once(',').chain(arguments.iter().map(Argument::tag)).collect::<String>());
+    for argument in arguments {
+        match argument {
+            Argument::Int(i) => packet.extend_from_slice(&i.to_be_bytes()),
+            Argument::Float(f) => packet.extend_from_slice(&f.to_be_bytes()),
+            Argument::String(s) => write_string(&mut packet, s),
+            Argument::Bool(_) => {} // in the type tag only
+        }
+    }
+    packet
+}
+
+fn write_string(packet: &mut Vec<u8>, string: &str) {
+    packet.extend_from_slice(string.as_bytes());
+    packet.resize((packet.len() / 4 + 1) * 4, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn decode_one(packet: &[u8]) -> (String, Vec<Argument>) {
+        let mut messages = Vec::new();
+        decode(packet, &mut messages).expect("valid packet");
+        assert_eq!(messages.len(), 1);
+        messages.remove(0)
+    }
+
+    fn bundle(messages: &[Vec<u8>]) -> Vec<u8> {
+        let mut packet = b"#bundle\0".to_vec();
+        packet.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]); // "immediately"
+        for message in messages {
+            packet.extend_from_slice(&(message.len() as i32).to_be_bytes());
+            packet.extend_from_slice(message);
+        }
+        packet
+    }
+
+    #[test]
+    fn round_trip() {
+        let arguments = vec![Argument::Int(-5), Argument::Float(0.5), Argument::String(String::from("Strings")), Argument::Bool(true), Argument::Bool(false)];
+        let packet = encode("/patch/current", &arguments);
+        assert_eq!(packet.len() % 4, 0);
+        assert_eq!(decode_one(&packet), (String::from("/patch/current"), arguments));
+    }
+
+    #[test]
+    fn string_padding() {
+        // a string always ends with at least one null, so one a multiple of 4 long gains 4 bytes
+        for (string, padded) in [("", 4), ("abc", 4), ("abcd", 8), ("abcde", 8), ("abcdefgh", 12)] {
+            let mut packet = Vec::new();
+            write_string(&mut packet, string);
+            assert_eq!(packet.len(), padded, "{:?}", string);
+            assert_eq!(&packet[..string.len()], string.as_bytes());
+            assert!(packet[string.len()..].iter().all(|b| *b == 0));
+            let address = format!("/{}", string);
+            assert_eq!(decode_one(&encode(&address, &[Argument::String(string.to_string())])), (address.clone(), vec![Argument::String(string.to_string())]));
+        }
+    }
+
+    #[test]
+    fn decode_bundle() {
+        let mut messages = Vec::new();
+        decode(&bundle(&[encode("/patch/next", &[]), encode("/patch/set", &[Argument::Int(2)])]), &mut messages).expect("valid bundle");
+        assert_eq!(messages, vec![(String::from("/patch/next"), vec![]), (String::from("/patch/set"), vec![Argument::Int(2)])]);
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut messages = Vec::new();
+        assert!(decode(b"/patch/next", &mut messages).is_none()); // no null
+        assert!(decode(&encode("/patch/set", &[Argument::Int(2)])[..16], &mut messages).is_none()); // argument cut off
+        assert!(decode(&bundle(&[encode("/patch/next", &[])])[..20], &mut messages).is_none());
+    }
+
+    /// Send a packet to the server, then let it handle requests until the client hears something back (or gives up)
+    fn exchange(server: &OscServer, device: &mut midi::ThruDevice, client: &UdpSocket, packet: &[u8]) -> Vec<(String, Vec<Argument>)> {
+        client.send_to(packet, server.socket.local_addr().unwrap()).unwrap();
+        let mut replies = Vec::new();
+        let mut buffer = [0; MAX_PACKET];
+        for _ in 0..20 {
+            server.handle_requests(Some(device));
+            while let Ok(length) = client.recv(&mut buffer) {
+                decode(&buffer[..length], &mut replies).expect("valid reply");
+            }
+            if !replies.is_empty() {
+                break;
+            }
+        }
+        replies
+    }
+
+    fn current(number: i32, name: &str) -> Vec<(String, Vec<Argument>)> {
+        vec![(String::from("/patch/current"), vec![Argument::Int(number), Argument::String(name.to_string())])]
+    }
+
+    #[test]
+    fn server_over_localhost() {
+        let dir = std::env::temp_dir().join(format!("midi-patch-changer-osc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let patch_file = dir.join("patches.json");
+        fs::write(&patch_file, "{\"name\":\"Piano\",\"program\":1},\n{\"name\":\"Strings\",\"program\":49},\n{\"name\":\"Organ\",\"program\":17}\n").unwrap();
+        let output = dir.join("out.mid");
+        fs::write(&output, "").unwrap();
+        let mut device = midi::ThruDevice::new(None, output.to_str().unwrap(), patch_file.to_str(), midi::DeviceOptions::default()).unwrap();
+        let server = OscServer::start(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(25))).unwrap();
+
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/subscribe", &[])), current(1, "Piano"));
+        // subscribed, so changes come back
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/next", &[Argument::Float(1.0)])), current(2, "Strings"));
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/set", &[Argument::String(String::from("Organ"))])), current(3, "Organ"));
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/set", &[Argument::Int(1)])), current(1, "Piano"));
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/set", &[Argument::String(String::from("Nope"))])),
+            vec![(String::from("/patch/error"), vec![Argument::String(String::from("No patch 'Nope'"))])]);
+        // both messages of a bundle are carried out, then subscribers hear about the result
+        assert_eq!(exchange(&server, &mut device, &client, &bundle(&[encode("/patch/next", &[]), encode("/patch/next", &[])])), current(3, "Organ"));
+        // a button release is ignored
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/prev", &[Argument::Float(0.0)])), vec![]);
+        assert_eq!(device.current_patch().map(|(number, _)| number), Some(3));
+        // changes made elsewhere (eg. the GUI) are sent too
+        device.set_patch(1);
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/other/fader", &[Argument::Float(0.5)])), current(2, "Strings"));
+
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/unsubscribe", &[])), vec![]);
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/next", &[])), vec![]);
+        assert_eq!(exchange(&server, &mut device, &client, &encode("/patch/current", &[])), current(3, "Organ"));
+
+        drop(server);
+        drop(device);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
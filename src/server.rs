@@ -0,0 +1,62 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How often the listener is checked for new connections
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A socket which other programs connect to
+pub trait Listener: Send + 'static {
+    type Connection: Send + 'static;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn accept_connection(&self) -> io::Result<Self::Connection>;
+}
+
+impl Listener for TcpListener {
+    type Connection = (TcpStream, SocketAddr);
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn accept_connection(&self) -> io::Result<Self::Connection> {
+        self.accept()
+    }
+}
+
+impl Listener for UnixListener {
+    type Connection = UnixStream;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn accept_connection(&self) -> io::Result<Self::Connection> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+/// Start a thread (with the given name) accepting connections until running is cleared,
+/// calling serve for each connection on a thread of its own
+pub fn accept_connections<L: Listener>(listener: L, name: &str, running: Arc<AtomicBool>, serve: impl FnOnce(L::Connection) + Clone + Send + 'static) -> io::Result<thread::JoinHandle<()>> {
+    // so that running is checked while no one is connecting
+    listener.set_nonblocking(true)?;
+    let client_name = format!("{}-client", name);
+    thread::Builder::new().name(name.to_string()).spawn(move || {
+        while running.load(Ordering::Relaxed) {
+            match listener.accept_connection() {
+                Ok(connection) => {
+                    let serve = serve.clone();
+                    let _ = thread::Builder::new().name(client_name.clone()).spawn(move || serve(connection));
+                },
+                // nothing waiting (or a client gave up before being accepted)
+                Err(_) => thread::sleep(ACCEPT_INTERVAL)
+            }
+        }
+    })
+}
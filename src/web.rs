@@ -0,0 +1,344 @@
+use crate::control::{self, Remote};
+use crate::midi;
+use crate::server;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Longest time to wait for a request to arrive, or for the patch system to carry it out
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often an idle WebSocket is pinged, to notice phones which have gone away
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// Appended to the client's key when accepting a WebSocket (RFC 6455)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_PING: u8 = 0x9;
+const PAGE: &str = include_str!("web.html");
+
+/// What the page shows, sent to every WebSocket as JSON whenever it changes
+#[derive(Serialize)]
+struct PageState {
+    previous: String,
+    current: String,
+    next: String
+}
+
+impl PageState {
+    fn of(device: Option<&midi::ThruDevice>) -> Self {
+        let name = |patch: Option<&midi::Patch>| patch.map(|patch| patch.name.clone()).unwrap_or_default();
+        match device {
+            Some(device) => Self {
+                previous: name(device.previous_patch()),
+                current: match device.current_patch() {
+                    Some((number, patch)) => format!("#{} {}", number, patch.name),
+                    None => String::from("No Patches")
+                },
+                next: name(device.next_patch())
+            },
+            None => Self {
+                previous: String::new(),
+                current: String::from("Choosing devices"),
+                next: String::new()
+            }
+        }
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// A command from a button on the page, waiting for the patch system to carry it out
+struct Request {
+    command: String,
+    from: SocketAddr,
+    reply: mpsc::Sender<String>
+}
+
+/// Shared between the patch system & the threads serving each client
+struct Shared {
+    /// The page state last sent, as JSON
+    state: Mutex<String>,
+    /// A channel to each open WebSocket
+    sockets: Mutex<Vec<mpsc::Sender<String>>>
+}
+
+/// Serves a page for phones showing the previous, current & next patch, with buttons to change patch:
+/// - GET / is the page, which is kept up to date through a WebSocket at GET /ws
+/// - GET /state is the page state as JSON
+/// - POST /next & POST /previous change the patch
+pub struct WebServer {
+    requests: mpsc::Receiver<Request>,
+    shared: Arc<Shared>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl WebServer {
+    pub fn start(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).map_err(|e| format!("Cannot listen on port {}: {}", port, e))?;
+        let (tx, rx) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(PageState::of(None).to_json()),
+            sockets: Mutex::new(Vec::new())
+        });
+        let shared_clone = shared.clone();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = server::accept_connections(listener, "midi-web", running.clone(), move |(stream, from)| serve_client(stream, from, tx, &shared_clone))
+            .map_err(|e| format!("Cannot listen on port {}: {}", port, e))?;
+        Ok(Self {
+            requests: rx,
+            shared,
+            running,
+            thread: Some(thread)
+        })
+    }
+}
+
+impl Remote for WebServer {
+    fn handle_requests(&self, mut device: Option<&mut midi::ThruDevice>) -> Vec<String> {
+        let mut handled = Vec::new();
+        while let Ok(request) = self.requests.try_recv() {
+            let reply = control::execute(&request.command, device.as_deref_mut()).pop().unwrap_or_default();
+            handled.push(format!("Web: {} from {} ({})", request.command, request.from, reply));
+            let _ = request.reply.send(reply);
+        }
+        // push every change to the pages, including those made by the GUI, MIDI triggers & other remotes
+        let state = PageState::of(device.as_deref()).to_json();
+        if let Ok(mut current) = self.shared.state.lock() {
+            if *current != state {
+                if let Ok(mut sockets) = self.shared.sockets.lock() {
+                    // drop any pages which have closed
+                    sockets.retain(|socket| socket.send(state.clone()).is_ok());
+                }
+                *current = state;
+            }
+        }
+        handled
+    }
+}
+
+impl Drop for WebServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // closes the WebSockets
+        if let Ok(mut sockets) = self.shared.sockets.lock() {
+            sockets.clear();
+        }
+    }
+}
+
+/// The parts of an HTTP request needed to answer it
+struct HttpRequest {
+    method: String,
+    path: String,
+    websocket_key: Option<String>
+}
+
+fn read_request(stream: &TcpStream) -> Option<HttpRequest> {
+    let mut lines = BufReader::new(stream).lines();
+    let request_line = lines.next()?.ok()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    let path = target.split('?').next().unwrap_or(target).to_string();
+    let mut websocket_key = None;
+    for line in lines {
+        let line = line.ok()?;
+        if line.is_empty() {
+            return Some(HttpRequest { method, path, websocket_key });
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Sec-WebSocket-Key") {
+                websocket_key = Some(value.trim().to_string());
+            }
+        }
+    }
+    None
+}
+
+fn serve_client(mut stream: TcpStream, from: SocketAddr, tx: mpsc::Sender<Request>, shared: &Shared) {
+    if stream.set_nonblocking(false).is_err() || stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err() {
+        return;
+    }
+    let request = match read_request(&stream) {
+        Some(request) => request,
+        None => return
+    };
+    let (status, content_type, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => ("200 OK", "text/html; charset=utf-8", PAGE.to_string()),
+        ("GET", "/state") => ("200 OK", "application/json", shared.state.lock().map(|state| state.clone()).unwrap_or_default()),
+        ("GET", "/ws") => match request.websocket_key {
+            Some(key) => return serve_websocket(stream, &key, shared),
+            None => ("400 Bad Request", "text/plain", String::from("Expected a WebSocket"))
+        },
+        ("POST", "/next") | ("POST", "/previous") => {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            let command = request.path[1..].to_string();
+            let reply = match tx.send(Request { command, from, reply: reply_tx }) {
+                Ok(()) => reply_rx.recv_timeout(REQUEST_TIMEOUT).ok(),
+                Err(_) => None
+            };
+            match reply {
+                Some(reply) => match reply.strip_prefix("ERR ") {
+                    Some(error) => ("409 Conflict", "text/plain", error.to_string()),
+                    None => ("200 OK", "text/plain", reply.trim_start_matches("OK").trim().to_string())
+                },
+                None => ("503 Service Unavailable", "text/plain", String::from("The patch system did not answer"))
+            }
+        },
+        (_, "/" | "/state" | "/ws" | "/next" | "/previous") => ("405 Method Not Allowed", "text/plain", String::from("Method not allowed")),
+        _ => ("404 Not Found", "text/plain", String::from("Not found"))
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body);
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// Send the page state, then every change, until the page closes.
+/// Nothing the page sends is needed, so it is never read.
+fn serve_websocket(mut stream: TcpStream, key: &str, shared: &Shared) {
+    let accept = base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+    let handshake = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept);
+    if stream.write_all(handshake.as_bytes()).is_err() {
+        return;
+    }
+    let (tx, rx) = mpsc::channel();
+    // holding the state lock so no change is missed between sending the state & subscribing
+    if let Ok(state) = shared.state.lock() {
+        let _ = tx.send(state.clone());
+        if let Ok(mut sockets) = shared.sockets.lock() {
+            sockets.push(tx);
+        }
+    }
+    loop {
+        let frame = match rx.recv_timeout(PING_INTERVAL) {
+            Ok(state) => frame(OPCODE_TEXT, state.as_bytes()),
+            Err(mpsc::RecvTimeoutError::Timeout) => frame(OPCODE_PING, &[]),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return
+        };
+        if stream.write_all(&frame).is_err() {
+            return;
+        }
+    }
+}
+
+/// A whole WebSocket message in one unmasked frame, as sent by a server
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= 0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        },
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// SHA-1, only used for the WebSocket handshake
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hash: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = hash;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, value) in hash.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(value);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, h) in digest.chunks_mut(4).zip(hash) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = u32::from_be_bytes([0, chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_accept_key() {
+        // the example from RFC 6455 section 1.3
+        let accept = base64(&sha1(format!("{}{}", "dGhlIHNhbXBsZSBub25jZQ==", WEBSOCKET_GUID).as_bytes()));
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+    }
+
+    #[test]
+    fn frame_length_boundaries() {
+        let cases: [(usize, &[u8]); 4] = [
+            (125, &[0x81, 125]),
+            (126, &[0x81, 126, 0, 126]),
+            (65535, &[0x81, 126, 0xFF, 0xFF]),
+            (65536, &[0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0])
+        ];
+        for (length, header) in cases {
+            let payload = vec![b'x'; length];
+            let framed = frame(OPCODE_TEXT, &payload);
+            assert_eq!(&framed[..header.len()], header, "header for {} bytes", length);
+            assert_eq!(&framed[header.len()..], &payload[..], "payload for {} bytes", length);
+        }
+    }
+}